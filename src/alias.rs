@@ -0,0 +1,64 @@
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Names of the same contig in different naming schemes, e.g. `chr1`, `1` and `NC_000001.11`.
+#[derive(Default)]
+pub struct ChromAlias {
+    names: Vec<Vec<String>>,
+    index: HashMap<String, usize>,
+}
+
+impl ChromAlias {
+    /// Read an alias table like UCSC `chromAlias.txt`: all names of a contig on one line,
+    /// separated by tab, skipping `#` comments.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| Error::Input(format!("Failed to read {:?}: {}", path, e)))?;
+        let mut alias = ChromAlias::default();
+        for line in content.lines() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let names: Vec<String> = line
+                .split('\t')
+                .map(|n| n.trim().to_string())
+                .filter(|n| !n.is_empty())
+                .collect();
+            for n in &names {
+                alias.index.insert(n.clone(), alias.names.len());
+            }
+            alias.names.push(names);
+        }
+        Ok(alias)
+    }
+
+    /// Find the name of a contig accepted by `exists`: the name itself, its aliases in the table,
+    /// or any of them with the `chr` prefix added or removed.
+    pub fn resolve(&self, name: &str, exists: impl Fn(&str) -> bool) -> Option<String> {
+        if exists(name) {
+            return Some(name.to_string());
+        }
+        let mut names = vec![name];
+        if let Some(&i) = self.index.get(name) {
+            names.extend(self.names[i].iter().map(|n| n.as_str()));
+        }
+        names
+            .into_iter()
+            .flat_map(|n| [n.to_string(), toggle_chr_prefix(n)])
+            .find(|n| exists(n))
+    }
+}
+
+// `chr1` <-> `1`, `chrM` <-> `MT`
+fn toggle_chr_prefix(name: &str) -> String {
+    match name {
+        "chrM" => "MT".to_string(),
+        "MT" => "chrM".to_string(),
+        _ => match name.strip_prefix("chr") {
+            Some(n) => n.to_string(),
+            None => format!("chr{name}"),
+        },
+    }
+}
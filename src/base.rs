@@ -1,20 +1,84 @@
-use csv;
-use indicatif::ParallelProgressIterator;
+use crate::alias::ChromAlias;
+use crate::columnar;
+use crate::error::{Error, Result};
+use crate::filter::ReadFilter;
+use crate::group::TagGroup;
+use crate::hts::{self, ThreadPool};
+use crate::output::{self, Output, OutputFormat, Row};
+use crate::region::{self, PosRecord};
+use crate::sample::Sample;
+use crate::samtools::{self, PileupRead};
+use crate::strand::LibraryType;
+use crate::umi::UmiDedup;
+use crate::vcf;
+use indicatif::{ParallelProgressIterator, ProgressBar};
 use itertools::Itertools;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use regex::Regex;
 use rust_htslib::bam::{self, Read};
 use rust_htslib::faidx;
-use serde::Deserialize;
+use std::cell::RefCell;
 use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::Arc;
 
-#[derive(Deserialize, Debug)]
-struct PosRecord {
-    chrom: String,
-    start: u32,
-    end: u32,
+// number of spans handed to each thread per batch
+const SPANS_PER_THREAD: usize = 64;
+
+// bam and fasta readers opened once per worker thread, with the shared htslib threads (dropped
+// after the readers). Bam files are left out when they don't fit in the limit of open files.
+struct Readers {
+    bams: Vec<bam::IndexedReader>,
+    fasta: faidx::Reader,
+    _thread_pool: Option<Arc<ThreadPool>>,
+}
+
+impl Readers {
+    fn open(
+        fasta_path: &PathBuf,
+        bam_path_list: &[PathBuf],
+        thread_pool: Option<&Arc<ThreadPool>>,
+        keep_bams: bool,
+    ) -> Result<Self> {
+        let bams = match keep_bams {
+            true => bam_path_list
+                .iter()
+                .map(|pth| hts::open_bam(pth, Some(fasta_path), thread_pool.map(|p| p.as_ref())))
+                .collect::<Result<_>>()?,
+            false => Vec::new(),
+        };
+        let fasta = faidx::Reader::from_path(fasta_path)
+            .map_err(|e| Error::Input(format!("Failed to open {:?}: {}", fasta_path, e)))?;
+        Ok(Readers {
+            bams,
+            fasta,
+            _thread_pool: thread_pool.cloned(),
+        })
+    }
+}
+
+// sequences inserted after a site, by reads on the forward and reverse strand
+type Insertions = (Vec<Vec<u8>>, Vec<Vec<u8>>);
+
+// output of a span: lines of text (wide tsv, vcf and samtools), or rows of counts (long format,
+// parquet and arrow)
+enum Report {
+    Text(String),
+    Rows(Vec<Row>),
+}
+
+// tabular output with the number of header lines, vcf/bcf output, or parquet/arrow output
+enum Writer {
+    Tsv(Output, usize),
+    Vcf(vcf::Writer),
+    Columnar(Box<columnar::Writer>),
+}
+
+thread_local! {
+    static READERS: RefCell<Option<Readers>> = const { RefCell::new(None) };
 }
 
 fn complement_base_code(c: u8) -> u8 {
@@ -45,6 +109,45 @@ fn complement_base_code(c: u8) -> u8 {
     }
 }
 
+// sequence inserted after the site, `N`s if the read has no base here
+fn inserted_seq(alignment: &bam::pileup::Alignment, record: &bam::Record, len: u32) -> Vec<u8> {
+    match alignment.qpos() {
+        Some(qpos) => {
+            let seq = record.seq();
+            (qpos + 1..qpos + 1 + len as usize)
+                .map(|j| seq[j])
+                .collect()
+        }
+        None => vec![b'N'; len as usize],
+    }
+}
+
+// regions split into spans of `chunk_size` bp (the last one of a region takes the remainder),
+// each with the start of its region, generated on the fly as a whole genome makes hundreds of
+// millions of spans
+fn split_spans(
+    regions: &[PosRecord],
+    chunk_size: u32,
+) -> impl Iterator<Item = (u32, PosRecord)> + '_ {
+    regions.iter().flat_map(move |record| {
+        let n_spans = ((record.end - record.start) / chunk_size).max(1);
+        (0..n_spans).map(move |k| {
+            let span = PosRecord {
+                chrom: record.chrom.clone(),
+                start: record.start + k * chunk_size,
+                end: if k + 1 == n_spans {
+                    record.end
+                } else {
+                    record.start + (k + 1) * chunk_size
+                },
+                name: record.name.clone(),
+                strand: record.strand,
+            };
+            (record.start, span)
+        })
+    })
+}
+
 fn build_thread_pool(j: usize) {
     rayon::ThreadPoolBuilder::new()
         .num_threads(j)
@@ -52,48 +155,76 @@ fn build_thread_pool(j: usize) {
         .unwrap();
 }
 
+#[allow(clippy::too_many_arguments)]
 fn parse_region(
     detection_span: &PosRecord,
-    chrom_tids: &Vec<u32>,
-    dna_bases: &Vec<u8>,
+    region_start: u32,
+    chrom_tids: &[Option<u32>],
+    dna_bases: &[u8],
     fasta_path: &PathBuf,
-    bam_path_list: &Vec<PathBuf>,
-    mut ouput_handle: &std::io::Stdout,
+    bam_path_list: &[PathBuf],
+    thread_pool: Option<&Arc<ThreadPool>>,
+    keep_bams: bool,
     min_depth: u32,
     mean_depth: u32,
     min_qual: u8,
+    read_filter: &ReadFilter,
+    mapq_excluded: &[AtomicU64],
+    umi_dedup: Option<&UmiDedup>,
+    tag_group: Option<&TagGroup>,
+    library_types: &[LibraryType],
     count_indel: bool,
     ignore_strand: bool,
     by_strand: bool,
-) -> String {
+    target_strand: bool,
+    output_format: OutputFormat,
+    long: bool,
+    output_mapq: bool,
+    output_pos: bool,
+    all_sites: bool,
+) -> Result<Report> {
     let mut p2depth: HashMap<(u32, usize), (u32, u32)> = HashMap::new();
     let mut p2base: HashMap<(u32, usize), (Vec<usize>, Vec<usize>)> = HashMap::new();
-    let mut p2ins: HashMap<(u32, usize), (Vec<u32>, Vec<u32>)> = HashMap::new();
+    let mut p2ins: HashMap<(u32, usize), Insertions> = HashMap::new();
     let mut p2del: HashMap<(u32, usize), (Vec<u32>, Vec<u32>)> = HashMap::new();
+    // bases of this site of the reads with an indel after it
+    let mut p2anchor: HashMap<(u32, usize), (Vec<usize>, Vec<usize>)> = HashMap::new();
+    let mut p2reads: HashMap<(u32, usize), Vec<PileupRead>> = HashMap::new();
 
-    let n_samples = bam_path_list.len();
+    let n_samples = bam_path_list.len() * tag_group.map_or(1, |g| g.n_groups());
     let chrom = &detection_span.chrom;
-    let start = *(&detection_span.start);
-    let end = *(&detection_span.end);
-    for (i, bam_path) in bam_path_list.iter().enumerate() {
-        // read bam file (SLOW STEP)
-        let mut bam_reader = bam::IndexedReader::from_path(&bam_path).unwrap();
+    let start = detection_span.start;
+    let end = detection_span.end;
+
+    // reuse the readers of this worker thread, open them on first use
+    let mut readers = match READERS.with(|cell| cell.borrow_mut().take()) {
+        Some(readers) => readers,
+        None => Readers::open(fasta_path, bam_path_list, thread_pool, keep_bams)?,
+    };
 
-        let tid = chrom_tids[i];
+    for (i, bam_path) in bam_path_list.iter().enumerate() {
+        // contig missing from this bam file, zero coverage
+        let tid = match chrom_tids[i] {
+            Some(tid) => tid,
+            None => continue,
+        };
+        // bam files not kept open are opened one at a time
+        let mut opened;
+        let bam_reader = match readers.bams.get_mut(i) {
+            Some(bam_reader) => bam_reader,
+            None => {
+                opened =
+                    hts::open_bam(bam_path, Some(fasta_path), thread_pool.map(|p| p.as_ref()))?;
+                &mut opened
+            }
+        };
 
-        bam_reader.fetch((tid, start, end)).unwrap();
+        let read_err = |e| Error::Input(format!("Failed to read {:?}: {}", bam_path, e));
+        bam_reader.fetch((tid, start, end)).map_err(read_err)?;
         // pileup over all covered sites
         for p in bam_reader.pileup() {
-            let pileup = p.unwrap();
-
-            let mut base_list_fwd: Vec<u8> = Vec::new();
-            let mut base_list_rev: Vec<u8> = Vec::new();
-            let mut insertion_list_fwd: Vec<u32> = Vec::new();
-            let mut insertion_list_rev: Vec<u32> = Vec::new();
-            let mut deletion_list_fwd: Vec<u32> = Vec::new();
-            let mut deletion_list_rev: Vec<u32> = Vec::new();
-            let mut total_reads_fwd = 0;
-            let mut total_reads_rev = 0;
+            let pileup = p.map_err(read_err)?;
+
             let ref_pos = pileup.pos();
             if (start <= ref_pos) && (ref_pos < end) {
                 // for alignment in pileup.alignments() {
@@ -105,376 +236,1028 @@ fn parse_region(
                         let record = aln.record();
                         (aln, record)
                     })
+                    .filter(|(aln, record)| {
+                        if !read_filter.pass_flags(record) {
+                            return false;
+                        }
+                        if !read_filter.pass_mapq(record) {
+                            // counted once, at the first site of the read in the region
+                            if ref_pos == (record.pos() as u32).max(region_start) {
+                                mapq_excluded[i].fetch_add(1, AtomicOrdering::Relaxed);
+                            }
+                            return false;
+                        }
+                        // trimmed read ends count neither as base nor as indel
+                        match aln.qpos() {
+                            Some(qpos) if read_filter.trims_ends() => {
+                                read_filter.pass_qpos(record, qpos)
+                            }
+                            _ => true,
+                        }
+                    })
                     .sorted_by(|a, b| Ord::cmp(a.1.qname(), b.1.qname()))
                     .group_by(|a| a.1.qname().to_owned());
 
-                for (_qname, reads) in grouped_by_qname.into_iter() {
-                    let (alignment, _record) = reads
-                        .into_iter()
-                        .max_by(|a, b| match a.1.mapq().cmp(&b.1.mapq()) {
-                            Ordering::Greater => Ordering::Greater,
-                            Ordering::Less => Ordering::Less,
-                            Ordering::Equal => {
-                                if a.1.flags() & 64 == 0 {
-                                    Ordering::Greater
-                                } else if b.1.flags() & 64 == 0 {
-                                    Ordering::Less
-                                } else {
-                                    Ordering::Greater
+                let selected = grouped_by_qname
+                    .into_iter()
+                    .map(|(_qname, reads)| {
+                        reads
+                            .into_iter()
+                            .max_by(|a, b| match a.1.mapq().cmp(&b.1.mapq()) {
+                                Ordering::Greater => Ordering::Greater,
+                                Ordering::Less => Ordering::Less,
+                                Ordering::Equal => {
+                                    if a.1.flags() & 64 == 0 {
+                                        Ordering::Greater
+                                    } else if b.1.flags() & 64 == 0 {
+                                        Ordering::Less
+                                    } else {
+                                        Ordering::Greater
+                                    }
                                 }
+                            })
+                            .unwrap()
+                    })
+                    .collect::<Vec<_>>();
+                // END: group by qname
+
+                // split reads into output columns, one per tag value when grouping by tag
+                let columns = match tag_group {
+                    Some(tag_group) => {
+                        let mut columns: HashMap<usize, Vec<_>> = HashMap::new();
+                        for read in selected {
+                            if let Some(g) = tag_group.group(&read.1) {
+                                columns
+                                    .entry(i * tag_group.n_groups() + g)
+                                    .or_default()
+                                    .push(read);
                             }
-                        })
-                        .unwrap();
-                    // END: group by qname
-
-                    let strand: char;
-                    if alignment.record().flags() & 128 == 128 {
-                        if alignment.record().flags() & 16 == 16 {
-                            strand = '+'
-                        } else {
-                            strand = '-'
-                        }
-                    } else {
-                        if alignment.record().flags() & 16 == 16 {
-                            strand = '-'
-                        } else {
-                            strand = '+'
                         }
+                        columns.into_iter().collect::<Vec<_>>()
+                    }
+                    None => vec![(i, selected)],
+                };
+
+                for (col, mut reads) in columns {
+                    if let Some(umi_dedup) = umi_dedup {
+                        reads = umi_dedup.dedup(reads);
                     }
 
-                    if !alignment.is_del() && !alignment.is_refskip() {
-                        let read_base = alignment.record().seq()[alignment.qpos().unwrap()];
-                        let read_qual = alignment.record().qual()[alignment.qpos().unwrap()];
-                        if read_qual >= min_qual {
-                            if strand == '+' {
-                                total_reads_fwd += 1;
-                                base_list_fwd.push(read_base);
-                            } else if strand == '-' {
-                                total_reads_rev += 1;
-                                base_list_rev.push(read_base);
+                    let mut base_list_fwd: Vec<u8> = Vec::new();
+                    let mut base_list_rev: Vec<u8> = Vec::new();
+                    let mut insertion_list_fwd: Vec<Vec<u8>> = Vec::new();
+                    let mut insertion_list_rev: Vec<Vec<u8>> = Vec::new();
+                    let mut deletion_list_fwd: Vec<u32> = Vec::new();
+                    let mut deletion_list_rev: Vec<u32> = Vec::new();
+                    let mut anchor_list_fwd: Vec<u8> = Vec::new();
+                    let mut anchor_list_rev: Vec<u8> = Vec::new();
+                    let mut total_reads_fwd = 0;
+                    let mut total_reads_rev = 0;
+                    let mut pileup_reads: Vec<PileupRead> = Vec::new();
+                    for (alignment, record) in reads {
+                        let strand = match library_types[i].strand(&record) {
+                            Some(strand) => strand,
+                            None => continue,
+                        };
+
+                        if output_format == OutputFormat::Samtools {
+                            // deletions and reference skips are kept whatever the quality
+                            let gap = alignment.is_del() || alignment.is_refskip();
+                            let qpos = alignment
+                                .qpos()
+                                .unwrap_or_else(|| samtools::qpos_after_gap(&record, ref_pos));
+                            let qual = record.qual().get(qpos).copied().unwrap_or(0);
+                            if gap || qual >= min_qual {
+                                pileup_reads.push(PileupRead {
+                                    base: if alignment.is_refskip() {
+                                        b'>'
+                                    } else if alignment.is_del() {
+                                        b'*'
+                                    } else {
+                                        record.seq()[qpos]
+                                    },
+                                    qual,
+                                    mapq: record.mapq(),
+                                    qpos,
+                                    reverse: record.is_reverse(),
+                                    head: alignment.is_head(),
+                                    tail: alignment.is_tail(),
+                                    ins: match alignment.indel() {
+                                        bam::pileup::Indel::Ins(len) => {
+                                            Some(inserted_seq(&alignment, &record, len))
+                                        }
+                                        _ => None,
+                                    },
+                                    del: match alignment.indel() {
+                                        bam::pileup::Indel::Del(len) => Some(len),
+                                        _ => None,
+                                    },
+                                });
                             }
                         }
-                    }
-                    if count_indel {
-                        // TODO: filter indel with nearby qual?
-                        match alignment.indel() {
-                            bam::pileup::Indel::Ins(len) => {
+
+                        let mut anchor = None;
+                        if !alignment.is_del() && !alignment.is_refskip() {
+                            let read_base = alignment.record().seq()[alignment.qpos().unwrap()];
+                            let read_qual = alignment.record().qual()[alignment.qpos().unwrap()];
+                            if read_qual >= min_qual {
+                                anchor = Some(read_base);
                                 if strand == '+' {
-                                    insertion_list_fwd.push(len);
+                                    total_reads_fwd += 1;
+                                    base_list_fwd.push(read_base);
                                 } else if strand == '-' {
-                                    insertion_list_rev.push(len);
+                                    total_reads_rev += 1;
+                                    base_list_rev.push(read_base);
                                 }
                             }
-                            bam::pileup::Indel::Del(len) => {
-                                if strand == '+' {
-                                    deletion_list_fwd.push(len);
-                                } else if strand == '-' {
-                                    deletion_list_rev.push(len);
+                        }
+                        // indels follow the base of this site, which has to pass `-q` as well
+                        if let (true, Some(anchor)) = (count_indel, anchor) {
+                            let is_indel = match alignment.indel() {
+                                bam::pileup::Indel::Ins(len) => {
+                                    // inserted bases follow the base of this site
+                                    let inserted = inserted_seq(&alignment, &record, len);
+                                    if strand == '+' {
+                                        insertion_list_fwd.push(inserted);
+                                    } else if strand == '-' {
+                                        insertion_list_rev.push(inserted);
+                                    }
+                                    true
+                                }
+                                bam::pileup::Indel::Del(len) => {
+                                    if strand == '+' {
+                                        deletion_list_fwd.push(len);
+                                    } else if strand == '-' {
+                                        deletion_list_rev.push(len);
+                                    }
+                                    true
                                 }
+                                _ => false,
+                            };
+                            if is_indel && strand == '+' {
+                                anchor_list_fwd.push(anchor);
+                            } else if is_indel && strand == '-' {
+                                anchor_list_rev.push(anchor);
                             }
-                            _ => {}
                         }
                     }
-                }
-                p2depth.insert((ref_pos, i), (total_reads_fwd, total_reads_rev));
+                    p2depth.insert((ref_pos, col), (total_reads_fwd, total_reads_rev));
+                    if output_format == OutputFormat::Samtools {
+                        p2reads.insert((ref_pos, col), pileup_reads);
+                    }
 
-                let base_counter_fwd: Vec<usize>;
-                let base_counter_rev: Vec<usize>;
-                // count forward bases
-                base_counter_fwd = dna_bases
-                    .clone()
-                    .into_iter()
-                    .map(|b| base_list_fwd.iter().filter(|&x| *x == b).count())
-                    .collect::<Vec<_>>();
-                // count reverse bases
-                base_counter_rev = dna_bases
-                    .clone()
-                    .into_iter()
-                    .map(|b| base_list_rev.iter().filter(|&x| *x == b).count())
-                    .collect::<Vec<_>>();
+                    // count forward bases
+                    let base_counter_fwd = dna_bases
+                        .iter()
+                        .map(|b| base_list_fwd.iter().filter(|&x| x == b).count())
+                        .collect::<Vec<_>>();
+                    // count reverse bases
+                    let base_counter_rev = dna_bases
+                        .iter()
+                        .map(|b| base_list_rev.iter().filter(|&x| x == b).count())
+                        .collect::<Vec<_>>();
 
-                p2base.insert((ref_pos, i), (base_counter_fwd, base_counter_rev));
+                    p2base.insert((ref_pos, col), (base_counter_fwd, base_counter_rev));
 
-                if count_indel {
-                    p2ins.insert((ref_pos, i), (insertion_list_fwd, insertion_list_rev));
-                    p2del.insert((ref_pos, i), (deletion_list_fwd, deletion_list_rev));
+                    if count_indel {
+                        p2ins.insert((ref_pos, col), (insertion_list_fwd, insertion_list_rev));
+                        p2del.insert((ref_pos, col), (deletion_list_fwd, deletion_list_rev));
+                        let anchor_counter = |list: &[u8]| {
+                            dna_bases
+                                .iter()
+                                .map(|b| list.iter().filter(|&x| x == b).count())
+                                .collect::<Vec<_>>()
+                        };
+                        p2anchor.insert(
+                            (ref_pos, col),
+                            (
+                                anchor_counter(&anchor_list_fwd),
+                                anchor_counter(&anchor_list_rev),
+                            ),
+                        );
+                    }
                 }
             }
         }
     }
 
-    // read fasta file
-    let fa_reader = &faidx::Reader::from_path(&fasta_path).unwrap();
+    // reference alleles of vcf (and deleted bases of samtools) span the deletions beyond the end
+    let ref_ext = match output_format {
+        OutputFormat::Samtools => p2reads
+            .values()
+            .flat_map(|reads| reads.iter().filter_map(|r| r.del))
+            .max()
+            .unwrap_or(0),
+        OutputFormat::Vcf | OutputFormat::Bcf => p2del
+            .values()
+            .flat_map(|(v1, v2)| v1.iter().chain(v2))
+            .max()
+            .copied()
+            .unwrap_or(0),
+        _ => 0,
+    };
     // input bed format is [start, end), but fa_reader is [start, end]
-    let fa_string = fa_reader
-        .fetch_seq_string(&chrom, start as usize, (end - 1) as usize)
-        .unwrap();
+    let fa_string = readers
+        .fasta
+        .fetch_seq_string(chrom, start as usize, (end - 1 + ref_ext) as usize)
+        .map_err(|e| Error::Input(format!("Failed to read {:?}: {}", fasta_path, e)))?;
+    READERS.with(|cell| *cell.borrow_mut() = Some(readers));
 
     let mut output_report: String = "".to_string();
+    let with_rows = long || output_format.is_columnar();
+    let mut rows: Vec<Row> = Vec::new();
     for p in start..std::cmp::min(end, start + fa_string.len() as u32) {
-        let rec_list = (0..n_samples)
-            .map(|x| {
-                if ignore_strand {
-                    let mut rec = vec![match p2base.get(&(p, x)) {
-                        Some((v1, v2)) => format!(
-                            "{}",
-                            (0..4)
-                                .map(|i| v1[i] + v2[i])
-                                .collect::<Vec<usize>>()
-                                .iter()
-                                .join(",")
-                        ),
-                        None => "0,0,0,0".to_string(),
-                    }];
-                    if count_indel {
-                        rec.push(match p2ins.get(&(p, x)) {
-                            Some((v1, v2)) => {
-                                format!("{}", v1.iter().chain(v2.iter()).join("|"))
-                            }
-                            None => "".to_string(),
-                        });
-                        rec.push(match p2del.get(&(p, x)) {
-                            Some((v1, v2)) => {
-                                format!("{}", v1.iter().chain(v2.iter()).join("|"))
-                            }
-                            None => "".to_string(),
-                        });
-                    }
-                    vec![rec.join(",")]
-                } else if by_strand {
-                    let mut rec = match p2base.get(&(p, x)) {
-                        Some((v1, v2)) => vec![v1.iter().join(","), v2.iter().rev().join(",")],
-                        None => vec!["0,0,0,0".to_string(), "0,0,0,0".to_string()],
+        if output_format == OutputFormat::Samtools {
+            // the printed depth, with deletions and reference skips
+            let depth_stat =
+                (0..n_samples).map(|x| p2reads.get(&(p, x)).map_or(0, |v| v.len() as u32));
+            // sites without any read are skipped, as by samtools without `-a`
+            let covered = all_sites
+                || (0..n_samples).any(|x| p2reads.get(&(p, x)).is_some_and(|v| !v.is_empty()));
+            if covered
+                & (depth_stat.clone().max().unwrap() >= min_depth)
+                & (depth_stat.clone().sum::<u32>() >= mean_depth * n_samples as u32)
+            {
+                let ref_seq = &fa_string.as_bytes()[(p - start) as usize..];
+                let val = (0..n_samples)
+                    .map(|x| {
+                        samtools::format_sample(
+                            p2reads.get(&(p, x)).map_or(&[], |v| v),
+                            ref_seq,
+                            output_mapq,
+                            output_pos,
+                        )
+                    })
+                    .join("\t");
+                output_report +=
+                    &format!("{}\t{}\t{}\t{}\n", chrom, p + 1, ref_seq[0] as char, val);
+            }
+            continue;
+        }
+        if matches!(output_format, OutputFormat::Vcf | OutputFormat::Bcf) {
+            let depth_stat = (0..n_samples).map(|x| match p2depth.get(&(p, x)) {
+                Some(val) => val.0 + val.1,
+                None => 0,
+            });
+            if (depth_stat.clone().max().unwrap() >= min_depth)
+                & (depth_stat.clone().sum::<u32>() >= mean_depth * n_samples as u32)
+            {
+                let counts = (0..n_samples)
+                    .map(|x| vcf::SiteCounts {
+                        bases: p2base
+                            .get(&(p, x))
+                            .map_or([&[0; 4], &[0; 4]], |(v1, v2)| [v1, v2]),
+                        ins: p2ins.get(&(p, x)).map_or([&[], &[]], |(v1, v2)| [v1, v2]),
+                        del: p2del.get(&(p, x)).map_or([&[], &[]], |(v1, v2)| [v1, v2]),
+                        anchors: p2anchor
+                            .get(&(p, x))
+                            .map_or([&[0; 4], &[0; 4]], |(v1, v2)| [v1, v2]),
+                    })
+                    .collect::<Vec<_>>();
+                output_report += &vcf::format_record(
+                    chrom,
+                    p,
+                    &fa_string.as_bytes()[(p - start) as usize..],
+                    &counts,
+                    count_indel,
+                );
+            }
+            continue;
+        }
+        // counts of each output column on each strand, or comma-separated fields of wide format
+        let (count_list, rec_list) = if with_rows {
+            let count_list = (0..n_samples)
+                .map(|x| {
+                    let (v1, v2) = p2base
+                        .get(&(p, x))
+                        .map_or((&[0; 4][..], &[0; 4][..]), |(v1, v2)| (&v1[..], &v2[..]));
+                    let (ins1, ins2) = p2ins
+                        .get(&(p, x))
+                        .map_or((0, 0), |(v1, v2)| (v1.len(), v2.len()));
+                    let (del1, del2) = p2del
+                        .get(&(p, x))
+                        .map_or((0, 0), |(v1, v2)| (v1.len(), v2.len()));
+                    let row = |rec: Vec<usize>, n_ins: usize, n_del: usize| {
+                        let mut rec = rec.into_iter().map(|n| n as u32).collect::<Vec<_>>();
+                        rec.push(rec.iter().sum());
+                        if count_indel {
+                            rec.push(n_ins as u32);
+                            rec.push(n_del as u32);
+                        }
+                        rec
                     };
-                    if count_indel {
-                        rec.append(&mut match p2ins.get(&(p, x)) {
-                            Some((v1, v2)) => vec![v1.iter().join("|"), v2.iter().join("|")],
-                            None => vec!["".to_string(), "".to_string()],
-                        });
-                        rec.append(&mut match p2del.get(&(p, x)) {
-                            Some((v1, v2)) => vec![v1.iter().join("|"), v2.iter().join("|")],
-                            None => vec!["".to_string(), "".to_string()],
-                        });
+                    if ignore_strand {
+                        vec![row(
+                            (0..4).map(|i| v1[i] + v2[i]).collect(),
+                            ins1 + ins2,
+                            del1 + del2,
+                        )]
+                    } else if by_strand {
+                        vec![
+                            row(v1.to_vec(), ins1, del1),
+                            row(v2.iter().rev().copied().collect(), ins2, del2),
+                        ]
+                    } else {
+                        vec![row([v1, v2].concat(), ins1 + ins2, del1 + del2)]
                     }
-                    vec![
-                        rec.iter().step_by(2).join(","),
-                        rec.iter().skip(1).step_by(2).join(","),
-                    ]
-                } else {
-                    let mut rec = vec![match p2base.get(&(p, x)) {
-                        Some((v1, v2)) => {
-                            format!("{},{}", v1.iter().join(","), v2.iter().join(","))
+                })
+                .collect::<Vec<_>>();
+            (count_list, Vec::new())
+        } else {
+            let rec_list = (0..n_samples)
+                .map(|x| {
+                    if ignore_strand {
+                        let mut rec = vec![match p2base.get(&(p, x)) {
+                            Some((v1, v2)) => (0..4).map(|i| v1[i] + v2[i]).join(","),
+                            None => "0,0,0,0".to_string(),
+                        }];
+                        if count_indel {
+                            rec.push(match p2ins.get(&(p, x)) {
+                                Some((v1, v2)) => {
+                                    v1.iter().chain(v2.iter()).map(|s| s.len()).join("|")
+                                }
+                                None => "".to_string(),
+                            });
+                            rec.push(match p2del.get(&(p, x)) {
+                                Some((v1, v2)) => v1.iter().chain(v2.iter()).join("|"),
+                                None => "".to_string(),
+                            });
                         }
-                        None => "0,0,0,0,0,0,0,0".to_string(),
-                    }];
-                    if count_indel {
-                        rec.push(match p2ins.get(&(p, x)) {
-                            Some((v1, v2)) => {
-                                format!("{}|{}", v1.iter().join("|"), v2.iter().join("|"))
-                            }
-                            None => "".to_string(),
-                        });
-                        rec.push(match p2del.get(&(p, x)) {
+                        vec![rec.join(",")]
+                    } else if by_strand {
+                        let mut rec = match p2base.get(&(p, x)) {
+                            Some((v1, v2)) => vec![v1.iter().join(","), v2.iter().rev().join(",")],
+                            None => vec!["0,0,0,0".to_string(), "0,0,0,0".to_string()],
+                        };
+                        if count_indel {
+                            rec.append(&mut match p2ins.get(&(p, x)) {
+                                Some((v1, v2)) => vec![
+                                    v1.iter().map(|s| s.len()).join("|"),
+                                    v2.iter().map(|s| s.len()).join("|"),
+                                ],
+                                None => vec!["".to_string(), "".to_string()],
+                            });
+                            rec.append(&mut match p2del.get(&(p, x)) {
+                                Some((v1, v2)) => vec![v1.iter().join("|"), v2.iter().join("|")],
+                                None => vec!["".to_string(), "".to_string()],
+                            });
+                        }
+                        vec![
+                            rec.iter().step_by(2).join(","),
+                            rec.iter().skip(1).step_by(2).join(","),
+                        ]
+                    } else {
+                        let mut rec = vec![match p2base.get(&(p, x)) {
                             Some((v1, v2)) => {
-                                format!("{}|{}", v1.iter().join("|"), v2.iter().join("|"))
+                                format!("{},{}", v1.iter().join(","), v2.iter().join(","))
                             }
-                            None => "".to_string(),
-                        });
+                            None => "0,0,0,0,0,0,0,0".to_string(),
+                        }];
+                        if count_indel {
+                            rec.push(match p2ins.get(&(p, x)) {
+                                Some((v1, v2)) => format!(
+                                    "{}|{}",
+                                    v1.iter().map(|s| s.len()).join("|"),
+                                    v2.iter().map(|s| s.len()).join("|")
+                                ),
+                                None => "".to_string(),
+                            });
+                            rec.push(match p2del.get(&(p, x)) {
+                                Some((v1, v2)) => {
+                                    format!("{}|{}", v1.iter().join("|"), v2.iter().join("|"))
+                                }
+                                None => "".to_string(),
+                            });
+                        }
+                        vec![rec.join(",")]
                     }
-                    vec![rec.join(",")]
-                }
-            })
-            .collect::<Vec<_>>();
+                })
+                .collect::<Vec<_>>();
+            (Vec::new(), rec_list)
+        };
+
+        // a line per site in wide format, or a row per site and output column in long format
+        let mut write_row = |strand: &'static str, r: &str, k: usize| {
+            let ref_base = r.chars().next().unwrap();
+            if !with_rows {
+                let val = rec_list.iter().map(|x| &x[k]).join("\t");
+                output_report += &format!("{}\t{}\t{}\t{}\t{}\n", chrom, p + 1, strand, r, val);
+            } else if long {
+                rows.extend(count_list.iter().enumerate().map(|(x, c)| Row {
+                    pos: p,
+                    strand,
+                    ref_base,
+                    label: Some(x),
+                    counts: c[k].clone(),
+                }));
+            } else {
+                rows.push(Row {
+                    pos: p,
+                    strand,
+                    ref_base,
+                    label: None,
+                    counts: count_list
+                        .iter()
+                        .flat_map(|c| c[k].iter().copied())
+                        .collect(),
+                });
+            }
+        };
 
         let r = &fa_string[(p - start) as usize..(p - start + 1) as usize];
         if ignore_strand {
             // filter depth
             let depth_stat = (0..n_samples).map(|x| match p2depth.get(&(p, x)) {
-                Some(val) => (*val).0 + (*val).1,
+                Some(val) => val.0 + val.1,
                 None => 0,
             });
             if (depth_stat.clone().max().unwrap() >= min_depth)
                 & (depth_stat.clone().sum::<u32>() >= mean_depth * n_samples as u32)
             {
-                let val = rec_list.iter().map(|x| &x[0]).join("\t");
-                output_report += &format!("{}\t{}\t{}\t{}\t{}\n", chrom, p + 1, '.', r, val);
+                write_row(".", r, 0);
             }
         } else if by_strand {
             let depth_stat = (0..n_samples).map(|x| match p2depth.get(&(p, x)) {
-                Some(val) => (*val).0,
+                Some(val) => val.0,
                 None => 0,
             });
-            if (depth_stat.clone().max().unwrap() >= min_depth)
+            if (!target_strand || detection_span.strand != Some('-'))
+                & (depth_stat.clone().max().unwrap() >= min_depth)
                 & (depth_stat.clone().sum::<u32>() >= mean_depth * n_samples as u32)
             {
-                let val = rec_list.iter().map(|x| &x[0]).join("\t");
-                output_report += &format!("{}\t{}\t{}\t{}\t{}\n", chrom, p + 1, "+", r, val);
+                write_row("+", r, 0);
             }
             let depth_stat = (0..n_samples).map(|x| match p2depth.get(&(p, x)) {
-                Some(val) => (*val).1,
+                Some(val) => val.1,
                 None => 0,
             });
-            if (depth_stat.clone().max().unwrap() >= min_depth)
+            if (!target_strand || detection_span.strand != Some('+'))
+                & (depth_stat.clone().max().unwrap() >= min_depth)
                 & (depth_stat.clone().sum::<u32>() >= mean_depth * n_samples as u32)
             {
-                let val = rec_list.iter().map(|x| &x[1]).join("\t");
-                output_report += &format!(
-                    "{}\t{}\t{}\t{}\t{}\n",
-                    chrom,
-                    p + 1,
-                    "-",
-                    complement_base_code(r.as_bytes()[0]) as char,
-                    val
-                );
+                let r = (complement_base_code(r.as_bytes()[0]) as char).to_string();
+                write_row("-", &r, 1);
             }
         } else {
             let depth_stat = (0..n_samples).map(|x| match p2depth.get(&(p, x)) {
-                Some(val) => (*val).0 + (*val).1,
+                Some(val) => val.0 + val.1,
                 None => 0,
             });
             if (depth_stat.clone().max().unwrap() >= min_depth)
                 & (depth_stat.clone().sum::<u32>() >= mean_depth * n_samples as u32)
             {
-                let val = rec_list.iter().map(|x| &x[0]).join("\t");
-                output_report += &format!("{}\t{}\t{}\t{}\t{}\n", chrom, p + 1, "+/-", r, val);
+                write_row("+/-", r, 0);
             }
         }
     }
 
-    _ = write!(ouput_handle, "{}", output_report);
-    return "".to_string();
+    Ok(match with_rows {
+        true => Report::Rows(rows),
+        false => Report::Text(output_report),
+    })
 }
 
+// whole contigs from the bam header, checked against the fasta index
+fn genome_regions(
+    fasta_path: &Path,
+    fai_len: &HashMap<String, u64>,
+    bam_path: &Path,
+    alias: &ChromAlias,
+    strict: bool,
+) -> Result<Vec<PosRecord>> {
+    let bam_reader = hts::open_bam(bam_path, Some(fasta_path), None)?;
+    let bam_header = bam_reader.header();
+    let mut regions = Vec::new();
+    for (tid, name) in bam_header.target_names().iter().enumerate() {
+        let chrom = String::from_utf8_lossy(name).to_string();
+        let len = bam_header.target_len(tid as u32).unwrap();
+        let fa_chrom = alias.resolve(&chrom, |c| fai_len.contains_key(c));
+        match fa_chrom.as_ref().map(|c| fai_len[c]) {
+            Some(l) if l == len => regions.push(PosRecord::new(fa_chrom.unwrap(), 0, len as u32)),
+            Some(l) => {
+                return Err(Error::Contig(format!(
+                    "Length of contig `{}` differs between {:?} ({}) and {:?} ({}).",
+                    chrom, bam_path, len, fasta_path, l
+                )));
+            }
+            None if strict => {
+                return Err(Error::Contig(format!(
+                    "Contig `{}` is not in {:?}.",
+                    chrom, fasta_path
+                )));
+            }
+            None => eprintln!("Contig `{}` is not in {:?}, skipped.", chrom, fasta_path),
+        }
+    }
+    Ok(regions)
+}
+
+// names of the counts of a sample: comma-separated fields of a column, or columns of long format
+// (with depth, and numbers instead of lengths of indels)
+fn field_names(ignore_strand: bool, by_strand: bool, count_indel: bool, long: bool) -> Vec<String> {
+    let bases = ["A", "C", "G", "T"];
+    let mut names: Vec<String> = if ignore_strand || by_strand {
+        bases.iter().map(|b| b.to_string()).collect()
+    } else {
+        ["fwd", "rev"]
+            .iter()
+            .flat_map(|s| bases.iter().map(move |b| format!("{}_{}", b, s)))
+            .collect()
+    };
+    if long {
+        names.push("depth".to_string());
+    }
+    if count_indel {
+        let indels = if long {
+            ["n_ins", "n_del"]
+        } else {
+            ["ins_len", "del_len"]
+        };
+        names.extend(indels.iter().map(|n| n.to_string()));
+    }
+    names
+}
+
+// contigs of vcf header, named as in the fasta: those in the bam header, then those only in the
+// fasta (zero coverage)
+fn vcf_contigs(
+    fasta_path: &Path,
+    fai_len: &HashMap<String, u64>,
+    bam_path: &Path,
+    alias: &ChromAlias,
+    chrom_set: &BTreeSet<String>,
+) -> Result<Vec<(String, u64)>> {
+    let bam_reader = hts::open_bam(bam_path, Some(fasta_path), None)?;
+    let bam_header = bam_reader.header();
+    let mut contigs: Vec<(String, u64)> = Vec::new();
+    for (tid, name) in bam_header.target_names().iter().enumerate() {
+        let chrom = String::from_utf8_lossy(name);
+        if let Some(fa_chrom) = alias.resolve(&chrom, |c| fai_len.contains_key(c)) {
+            contigs.push((fa_chrom, bam_header.target_len(tid as u32).unwrap()));
+        }
+    }
+    for chrom in chrom_set {
+        if !contigs.iter().any(|(c, _)| c == chrom) {
+            contigs.push((chrom.clone(), fai_len[chrom]));
+        }
+    }
+    Ok(contigs)
+}
+
+// collect all values of the tag carried by reads within the target regions, up to `max_values`
+fn collect_tag_values(
+    tag: [u8; 2],
+    regions: &[PosRecord],
+    chrom_map: &HashMap<String, Vec<Option<u32>>>,
+    fasta_path: &Path,
+    bam_path_list: &[PathBuf],
+    read_filter: &ReadFilter,
+    max_values: usize,
+) -> Result<Vec<String>> {
+    let tag_group = TagGroup::new(tag, Vec::new(), false);
+    let mut values: BTreeSet<String> = BTreeSet::new();
+    for (i, bam_path) in bam_path_list.iter().enumerate() {
+        let mut bam_reader = hts::open_bam(bam_path, Some(fasta_path), None)?;
+        let read_err = |e| Error::Input(format!("Failed to read {:?}: {}", bam_path, e));
+        for r in regions {
+            let tid = match chrom_map[&r.chrom][i] {
+                Some(tid) => tid,
+                None => continue,
+            };
+            bam_reader.fetch((tid, r.start, r.end)).map_err(read_err)?;
+            for r in bam_reader.records() {
+                let record = r.map_err(read_err)?;
+                if !read_filter.pass_flags(&record) || !read_filter.pass_mapq(&record) {
+                    continue;
+                }
+                if let Some(v) = tag_group.value(&record) {
+                    if !values.contains(v) {
+                        values.insert(v.to_string());
+                    }
+                    if values.len() > max_values {
+                        return Err(Error::Param(format!(
+                            "More than {} values of tag `{}` are found in the target regions, give the values to report with `--tag-whitelist` (or raise `--max-tag-values`).",
+                            max_values,
+                            String::from_utf8_lossy(&tag)
+                        )));
+                    }
+                }
+            }
+        }
+    }
+    Ok(values.into_iter().collect())
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn run(
-    region_path: PathBuf,
+    region_path: Option<PathBuf>,
+    region_strs: Vec<String>,
+    include_contig: Option<Regex>,
+    exclude_contig: Option<Regex>,
+    alias: ChromAlias,
     fasta_path: PathBuf,
-    bam_path_list: Vec<PathBuf>,
+    samples: Vec<Sample>,
+    output_path: Option<PathBuf>,
+    output_format: OutputFormat,
+    hts_threads: usize,
+    auto_index: bool,
+    strict: bool,
     min_depth: u32,
     mean_depth: u32,
     min_qual: u8,
+    read_filter: ReadFilter,
+    umi_dedup: Option<UmiDedup>,
+    group_tag: Option<[u8; 2]>,
+    tag_whitelist: Option<PathBuf>,
+    keep_unassigned: bool,
+    max_tag_values: usize,
     count_indel: bool,
     without_header: bool,
+    long: bool,
+    field_header: bool,
+    output_mapq: bool,
+    output_pos: bool,
+    all_sites: bool,
     ignore_strand: bool,
     by_strand: bool,
+    target_strand: bool,
+    keep_overlap: bool,
     chunk_size: u32,
     n_jobs: usize,
     log_type: u8,
-) {
+) -> Result<()> {
     // check parameters
+    if chunk_size == 0 {
+        return Err(Error::Param(
+            "`--chunk-size` must be at least 1.".to_string(),
+        ));
+    }
+    if n_jobs == 0 {
+        return Err(Error::Param("`--threads` must be at least 1.".to_string()));
+    }
     if by_strand & ignore_strand {
-        eprintln!("Output records by strand, but `--ignore-strand` flag is set.");
-        std::process::exit(1);
+        return Err(Error::Param(
+            "Output records by strand, but `--ignore-strand` flag is set.".to_string(),
+        ));
+    }
+    if target_strand & ignore_strand {
+        return Err(Error::Param(
+            "Output records by target strand, but `--ignore-strand` flag is set.".to_string(),
+        ));
+    }
+    // collecting the values would read every bam file in full first
+    if group_tag.is_some()
+        && tag_whitelist.is_none()
+        && region_path.is_none()
+        && region_strs.is_empty()
+    {
+        return Err(Error::Param(
+            "Grouping by tag over the whole genome needs `--tag-whitelist`.".to_string(),
+        ));
     }
 
-    // A, C, G, T
-    let dna_bases = &vec![65, 67, 71, 84];
-
-    // read region file
-    let mut pos_reader = csv::ReaderBuilder::new()
-        .has_headers(false)
-        .delimiter(b'\t')
-        .from_path(&region_path)
-        .unwrap();
+    let bam_path_list: Vec<PathBuf> = samples.iter().map(|s| s.path.clone()).collect();
+    let library_types: Vec<LibraryType> = samples.iter().map(|s| s.library_type).collect();
+    hts::check_indexes(Some(&fasta_path), &bam_path_list, auto_index, hts_threads)?;
+    let keep_bams = hts::fit_open_files(bam_path_list.len(), n_jobs);
+    if !keep_bams {
+        eprintln!(
+            "Keeping {} bam/cram files open in each of {} threads is over the limit of open files, they are opened for each span instead.",
+            bam_path_list.len(),
+            n_jobs
+        );
+    }
+    let per_site = matches!(
+        output_format,
+        OutputFormat::Vcf | OutputFormat::Bcf | OutputFormat::Samtools
+    );
+    if per_site && (by_strand || target_strand) {
+        return Err(Error::Param(
+            "Output vcf/bcf/samtools has one record per site, but `--split-strand` or `--target-strand` flag is set."
+                .to_string(),
+        ));
+    }
+    if per_site && long {
+        return Err(Error::Param(
+            "Output vcf/bcf/samtools has one record per site, but `--long` flag is set."
+                .to_string(),
+        ));
+    }
+    if (output_mapq || output_pos || all_sites) && output_format != OutputFormat::Samtools {
+        return Err(Error::Param(
+            "`--output-mapq`, `--output-bp` and `--all-sites` apply to samtools format only."
+                .to_string(),
+        ));
+    }
+    let indexed = output::is_indexed(output_path.as_deref(), output_format);
+    if indexed && keep_overlap {
+        return Err(Error::Param(
+            "Indexed output needs sorted positions, but `--keep-overlap` flag is set.".to_string(),
+        ));
+    }
 
-    // prepare output
-    let handle = std::io::stdout();
+    // A, C, G, T
+    let dna_bases = &[65, 67, 71, 84];
 
-    if !without_header {
-        let mut header_line = "Chrom\tPos\tStrand\tRef".to_string();
-        for pth in &bam_path_list {
-            header_line += &format!("\t{}", pth.to_str().unwrap().to_string())
+    let keep_contig = |chrom: &str| {
+        include_contig.as_ref().is_none_or(|re| re.is_match(chrom))
+            && !exclude_contig.as_ref().is_some_and(|re| re.is_match(chrom))
+    };
+    let fai_len = region::fai_lengths(&fasta_path)?;
+    let mut regions = match &region_path {
+        Some(region_path) => region::read_bed(region_path)?,
+        None if region_strs.is_empty() => {
+            genome_regions(&fasta_path, &fai_len, &bam_path_list[0], &alias, strict)?
         }
-        _ = write!(&handle, "{}\n", header_line);
+        None => Vec::new(),
+    };
+    for s in &region_strs {
+        regions.push(region::parse_region_str(s, &fai_len, &alias)?);
     }
-    // Read through all records in region.
-    //let mut spans: Vec<(String, u32, u32)> = Vec::new();
-    let mut spans: Vec<PosRecord> = Vec::new();
-
-    let mut chrom_set: HashSet<String> = HashSet::new();
-    for (_i, record) in pos_reader.deserialize().enumerate() {
-        let record: PosRecord = record.unwrap();
-        let chrom: String = record.chrom.clone();
-        let start = record.start as u32;
-        let end = record.end as u32;
-        chrom_set.insert(chrom.clone());
-        // split into chunks
-        let mut splited_start = start;
-        for _ in 1..(end - start) / chunk_size {
-            spans.push(PosRecord {
-                chrom: chrom.clone(),
-                start: splited_start,
-                end: splited_start + chunk_size,
-            });
-            splited_start = splited_start + chunk_size;
-        }
-        spans.push(PosRecord {
-            chrom: chrom.clone(),
-            start: splited_start,
-            end,
-        });
+    regions = region::clip_regions(regions, &fai_len, &alias, strict)?;
+    regions.retain(|r| keep_contig(&r.chrom));
+    let chrom_set: BTreeSet<String> = regions.iter().map(|r| r.chrom.clone()).collect();
+    if !keep_overlap {
+        regions = region::merge_regions(regions, target_strand);
+    }
+    // regions on opposite strands are kept apart by `--target-strand`
+    if indexed
+        && regions
+            .windows(2)
+            .any(|w| w[0].chrom == w[1].chrom && w[1].start < w[0].end)
+    {
+        return Err(Error::Param(
+            "Indexed output needs sorted positions, but target regions on opposite strands overlap."
+                .to_string(),
+        ));
     }
 
     // convert chromosome name into tid (can improve speed)
-    let mut chrom_map: HashMap<String, Vec<u32>> = HashMap::new();
+    let mut chrom_map: HashMap<String, Vec<Option<u32>>> = HashMap::new();
     for bam_path in bam_path_list.iter() {
-        let bam_reader = bam::IndexedReader::from_path(&bam_path).unwrap();
+        let bam_reader = hts::open_bam(bam_path, Some(&fasta_path), None)?;
         let bam_header = bam_reader.header().clone();
         for chrom in &chrom_set {
-            chrom_map
-                .entry((*chrom).to_string())
-                .or_insert(Vec::new())
-                .push(bam_header.tid(chrom.as_bytes()).unwrap());
+            let tid = alias
+                .resolve(chrom, |c| bam_header.tid(c.as_bytes()).is_some())
+                .and_then(|c| bam_header.tid(c.as_bytes()));
+            if tid.is_none() {
+                if strict {
+                    return Err(Error::Contig(format!(
+                        "Contig `{}` is not in {:?}.",
+                        chrom, bam_path
+                    )));
+                }
+                eprintln!(
+                    "Contig `{}` is not in {:?}, counted as zero coverage.",
+                    chrom, bam_path
+                );
+            }
+            chrom_map.entry(chrom.to_string()).or_default().push(tid);
         }
     }
-    // run in parallel
-    build_thread_pool(n_jobs);
-    if log_type != 2 {
-        spans
-            .par_iter()
-            .map(|s| {
-                parse_region(
-                    s,
-                    &chrom_map[&s.chrom],
-                    dna_bases,
+    let mapq_excluded = bam_path_list
+        .iter()
+        .map(|_| AtomicU64::new(0))
+        .collect::<Vec<_>>();
+
+    let tag_group = group_tag
+        .map(|tag| match &tag_whitelist {
+            Some(pth) => TagGroup::from_whitelist(tag, pth, keep_unassigned),
+            None => Ok(TagGroup::new(
+                tag,
+                collect_tag_values(
+                    tag,
+                    &regions,
+                    &chrom_map,
                     &fasta_path,
                     &bam_path_list,
-                    &handle,
-                    min_depth,
-                    mean_depth,
-                    min_qual,
-                    count_indel,
-                    ignore_strand,
-                    by_strand,
-                );
-                format!("{}:{}-{}", s.chrom, s.start, s.end)
+                    &read_filter,
+                    max_tag_values,
+                )?,
+                keep_unassigned,
+            )),
+        })
+        .transpose()?;
+    if let Some(g) = &tag_group {
+        if g.n_groups() == 0 {
+            return Err(Error::Input(format!(
+                "No value of tag `{}` found in the target regions.",
+                String::from_utf8_lossy(&g.tag)
+            )));
+        }
+    }
+
+    // prepare output
+    let mut column_names = Vec::new();
+    for sample in &samples {
+        match &tag_group {
+            Some(g) if samples.len() == 1 => {
+                column_names.extend(g.names().into_iter().map(|n| n.to_string()))
+            }
+            Some(g) => column_names.extend(
+                g.names()
+                    .into_iter()
+                    .map(|n| format!("{}:{}", sample.name, n)),
+            ),
+            None => column_names.push(sample.name.clone()),
+        }
+    }
+    let has_group = samples.iter().any(|s| s.group.is_some());
+    // typed columns are named as those of long format
+    let field_names = field_names(
+        ignore_strand,
+        by_strand || target_strand,
+        count_indel,
+        long || output_format.is_columnar(),
+    );
+    // sample (and group) of each output column in long format
+    let long_labels = long.then(|| {
+        let n_groups = tag_group.as_ref().map_or(1, |g| g.n_groups());
+        column_names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| match &samples[i / n_groups].group {
+                _ if !has_group => vec![name.clone()],
+                Some(group) => vec![name.clone(), group.clone()],
+                None => vec![name.clone(), ".".to_string()],
             })
-            .inspect(|x| {
-                if log_type == 1 {
-                    eprintln!("{}", x)
+            .collect::<Vec<_>>()
+    });
+    let mut writer = match output_format {
+        OutputFormat::Tsv | OutputFormat::Samtools => {
+            Writer::Tsv(Output::create(output_path.as_deref(), hts_threads)?, 0)
+        }
+        OutputFormat::Vcf | OutputFormat::Bcf => {
+            let contigs =
+                vcf_contigs(&fasta_path, &fai_len, &bam_path_list[0], &alias, &chrom_set)?;
+            let header = vcf::header(&fasta_path, &contigs, &column_names, count_indel);
+            Writer::Vcf(vcf::Writer::create(
+                output_path.as_deref(),
+                output_format,
+                &header,
+                hts_threads,
+            )?)
+        }
+        OutputFormat::Parquet | OutputFormat::Arrow => {
+            let (labels, counts) = if long {
+                let labels = if has_group {
+                    vec!["Sample".to_string(), "Group".to_string()]
+                } else {
+                    vec!["Sample".to_string()]
+                };
+                (labels, field_names.clone())
+            } else {
+                // a column of each count of each sample
+                let counts = column_names
+                    .iter()
+                    .flat_map(|c| field_names.iter().map(move |f| format!("{}:{}", c, f)))
+                    .collect();
+                (Vec::new(), counts)
+            };
+            Writer::Columnar(Box::new(columnar::Writer::create(
+                output_path.as_deref(),
+                output_format,
+                &labels,
+                long_labels.clone().unwrap_or_default(),
+                &counts,
+            )?))
+        }
+    };
+    // samtools format has no header
+    let without_header = without_header || output_format == OutputFormat::Samtools;
+    if let (Writer::Tsv(output, header_lines), true) = (&mut writer, long && !without_header) {
+        let header_line = format!(
+            "Chrom\tPos\tStrand\tRef\tSample\t{}{}",
+            if has_group { "Group\t" } else { "" },
+            field_names.join("\t")
+        );
+        writeln!(output, "{}", header_line)?;
+        *header_lines += 1;
+    } else if let (Writer::Tsv(output, header_lines), false) = (&mut writer, without_header) {
+        // order of the comma-separated counts in each column
+        if field_header {
+            writeln!(output, "#Fields\t{}", field_names.join(","))?;
+            *header_lines += 1;
+        }
+        // group of each column, aligned with the header line below
+        if has_group {
+            let mut group_line = "#Group\t\t\t".to_string();
+            for sample in &samples {
+                let group = sample.group.as_deref().unwrap_or(".");
+                for _ in 0..tag_group.as_ref().map_or(1, |g| g.n_groups()) {
+                    group_line += &format!("\t{}", group);
                 }
-            })
-            .collect::<String>();
+            }
+            writeln!(output, "{}", group_line)?;
+            *header_lines += 1;
+        }
+        let header_line = format!("Chrom\tPos\tStrand\tRef\t{}", column_names.join("\t"));
+        writeln!(output, "{}", header_line)?;
+        *header_lines += 1;
+    }
+
+    // run in parallel, batch by batch to keep spans in order with bounded memory
+    build_thread_pool(n_jobs);
+    let thread_pool = match hts_threads {
+        0 => None,
+        n => Some(Arc::new(ThreadPool::new(n)?)),
+    };
+    let pb = if log_type == 2 {
+        let n_spans: u64 = regions
+            .iter()
+            .map(|r| ((r.end - r.start) / chunk_size).max(1) as u64)
+            .sum();
+        ProgressBar::new(n_spans)
     } else {
-        spans
+        ProgressBar::hidden()
+    };
+    let spans = split_spans(&regions, chunk_size).chunks(n_jobs * SPANS_PER_THREAD);
+    for batch in &spans {
+        let batch = batch.collect::<Vec<_>>();
+        let reports = batch
             .par_iter()
-            .progress_count(spans.len() as u64)
-            .map(|s| {
-                parse_region(
+            .progress_with(pb.clone())
+            .map(|(region_start, s)| {
+                let report = parse_region(
                     s,
+                    *region_start,
                     &chrom_map[&s.chrom],
                     dna_bases,
                     &fasta_path,
                     &bam_path_list,
-                    &handle,
+                    thread_pool.as_ref(),
+                    keep_bams,
                     min_depth,
                     mean_depth,
                     min_qual,
+                    &read_filter,
+                    &mapq_excluded,
+                    umi_dedup.as_ref(),
+                    tag_group.as_ref(),
+                    &library_types,
                     count_indel,
                     ignore_strand,
-                    by_strand,
+                    by_strand || target_strand,
+                    target_strand,
+                    output_format,
+                    long,
+                    output_mapq,
+                    output_pos,
+                    all_sites,
                 );
-                format!("{}:{}-{}", s.chrom, s.start, s.end)
+                if log_type == 1 {
+                    eprintln!("{}:{}-{}", s.chrom, s.start, s.end)
+                }
+                report
             })
-            .collect::<String>();
+            .collect::<Result<Vec<Report>>>()?;
+        for (report, (_, s)) in reports.into_iter().zip(&batch) {
+            match (&mut writer, report) {
+                (Writer::Tsv(output, _), Report::Text(text)) => write!(output, "{}", text)?,
+                (Writer::Tsv(output, _), Report::Rows(rows)) => {
+                    // long format, the only one of tsv with rows
+                    let labels = long_labels.as_ref().unwrap();
+                    for row in rows {
+                        writeln!(
+                            output,
+                            "{}\t{}\t{}\t{}\t{}\t{}",
+                            s.chrom,
+                            row.pos + 1,
+                            row.strand,
+                            row.ref_base,
+                            labels[row.label.unwrap()].join("\t"),
+                            row.counts.iter().join("\t")
+                        )?;
+                    }
+                }
+                (Writer::Vcf(vcf_writer), Report::Text(text)) => {
+                    for line in text.lines() {
+                        vcf_writer.write_line(line)?;
+                    }
+                }
+                (Writer::Columnar(columnar_writer), Report::Rows(rows)) => {
+                    columnar_writer.write_span(&s.chrom, &rows)?
+                }
+                _ => unreachable!("rows are made for long and columnar output only"),
+            }
+        }
+    }
+    match writer {
+        Writer::Tsv(output, header_lines) => output.finish(header_lines, false)?,
+        Writer::Vcf(vcf_writer) => vcf_writer.finish()?,
+        Writer::Columnar(columnar_writer) => columnar_writer.finish()?,
+    }
+    pb.finish();
+
+    if read_filter.min_mapq > 0 || read_filter.unique_only {
+        for (sample, n) in samples.iter().zip(mapq_excluded.iter()) {
+            eprintln!(
+                "{}\t{} reads excluded by MAPQ filter",
+                sample.name,
+                n.load(AtomicOrdering::Relaxed)
+            );
+        }
     }
+    Ok(())
 }
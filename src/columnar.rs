@@ -0,0 +1,163 @@
+use crate::error::{Error, Result};
+use crate::output::{OutputFormat, Row};
+use arrow_array::builder::{StringBuilder, UInt32Builder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+// rows buffered before a row group (or record batch) is written, at the end of a span
+const ROWS_PER_GROUP: usize = 1 << 20;
+
+enum Sink {
+    Parquet(ArrowWriter<Box<dyn Write + Send>>),
+    Arrow(arrow_ipc::writer::FileWriter<Box<dyn Write + Send>>),
+}
+
+/// Writer of Parquet or Arrow IPC files, with typed columns: `Chrom`, `Pos` (1-based), `Strand`
+/// and `Ref`, the text columns of labels (e.g. `Sample` of long format), then the counts.
+/// Row groups hold whole spans, so each is sorted by position.
+pub struct Writer {
+    sink: Sink,
+    schema: SchemaRef,
+    /// values of the label columns of each output column in long format
+    label_values: Vec<Vec<String>>,
+    pos: UInt32Builder,
+    texts: Vec<StringBuilder>,
+    counts: Vec<UInt32Builder>,
+    rows: usize,
+}
+
+fn write_err(e: impl std::error::Error + Send + Sync + 'static) -> Error {
+    Error::Write(io::Error::other(e))
+}
+
+impl Writer {
+    pub fn create(
+        path: Option<&Path>,
+        format: OutputFormat,
+        labels: &[String],
+        label_values: Vec<Vec<String>>,
+        counts: &[String],
+    ) -> Result<Self> {
+        let mut fields = vec![
+            Field::new("Chrom", DataType::Utf8, false),
+            Field::new("Pos", DataType::UInt32, false),
+            Field::new("Strand", DataType::Utf8, false),
+            Field::new("Ref", DataType::Utf8, false),
+        ];
+        fields.extend(labels.iter().map(|n| Field::new(n, DataType::Utf8, false)));
+        fields.extend(
+            counts
+                .iter()
+                .map(|n| Field::new(n, DataType::UInt32, false)),
+        );
+        let schema = Arc::new(Schema::new(fields));
+
+        let out: Box<dyn Write + Send> = match path {
+            Some(p) => Box::new(BufWriter::new(File::create(p).map_err(|e| {
+                Error::Write(io::Error::other(format!("Failed to create {:?}: {}", p, e)))
+            })?)),
+            None => Box::new(BufWriter::new(io::stdout())),
+        };
+        let sink = match format {
+            OutputFormat::Parquet => {
+                let props = WriterProperties::builder()
+                    .set_compression(Compression::ZSTD(ZstdLevel::default()))
+                    // row groups are cut at the end of spans instead
+                    .set_max_row_group_size(usize::MAX)
+                    .build();
+                Sink::Parquet(
+                    ArrowWriter::try_new(out, schema.clone(), Some(props)).map_err(write_err)?,
+                )
+            }
+            _ => Sink::Arrow(
+                arrow_ipc::writer::FileWriter::try_new(out, &schema).map_err(write_err)?,
+            ),
+        };
+        Ok(Writer {
+            sink,
+            schema,
+            label_values,
+            pos: UInt32Builder::new(),
+            texts: (0..3 + labels.len())
+                .map(|_| StringBuilder::new())
+                .collect(),
+            counts: counts.iter().map(|_| UInt32Builder::new()).collect(),
+            rows: 0,
+        })
+    }
+
+    /// Append the rows of a span on `chrom`, and write a row group once enough rows are buffered.
+    pub fn write_span(&mut self, chrom: &str, rows: &[Row]) -> Result<()> {
+        for row in rows {
+            self.texts[0].append_value(chrom);
+            self.pos.append_value(row.pos + 1);
+            self.texts[1].append_value(row.strand);
+            self.texts[2].append_value(row.ref_base.to_string());
+            if let Some(label) = row.label {
+                for (builder, value) in self.texts[3..].iter_mut().zip(&self.label_values[label]) {
+                    builder.append_value(value);
+                }
+            }
+            for (builder, n) in self.counts.iter_mut().zip(&row.counts) {
+                builder.append_value(*n);
+            }
+            self.rows += 1;
+        }
+        if self.rows >= ROWS_PER_GROUP {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // write the buffered rows as a row group (or record batch)
+    fn flush(&mut self) -> Result<()> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(self.texts[0].finish()),
+            Arc::new(self.pos.finish()),
+        ];
+        columns.extend(
+            self.texts[1..]
+                .iter_mut()
+                .map(|b| Arc::new(b.finish()) as ArrayRef),
+        );
+        columns.extend(
+            self.counts
+                .iter_mut()
+                .map(|b| Arc::new(b.finish()) as ArrayRef),
+        );
+        let batch = RecordBatch::try_new(self.schema.clone(), columns).map_err(write_err)?;
+        match &mut self.sink {
+            Sink::Parquet(w) => {
+                w.write(&batch).map_err(write_err)?;
+                w.flush().map_err(write_err)?;
+            }
+            Sink::Arrow(w) => w.write(&batch).map_err(write_err)?,
+        }
+        self.rows = 0;
+        Ok(())
+    }
+
+    /// Write the last rows and the footer of the file.
+    pub fn finish(mut self) -> Result<()> {
+        self.flush()?;
+        let mut out = match self.sink {
+            Sink::Parquet(w) => w.into_inner().map_err(write_err)?,
+            Sink::Arrow(mut w) => {
+                w.finish().map_err(write_err)?;
+                w.into_inner().map_err(write_err)?
+            }
+        };
+        out.flush()?;
+        Ok(())
+    }
+}
@@ -1,57 +1,129 @@
-use csv;
-use rust_htslib::bam;
+use crate::alias::ChromAlias;
+use crate::error::{Error, Result};
+use crate::filter::ReadFilter;
+use crate::hts;
+use crate::output::Output;
+use crate::region;
 use rust_htslib::bam::Read;
 use rust_htslib::faidx;
-use serde::Deserialize;
 use std::char;
 use std::io;
 use std::path::PathBuf;
-use std::str;
 
-#[derive(Deserialize, Debug)]
-struct PosRecord {
-    chrom: String,
-    start: u32,
-    end: u32,
-}
-
-pub fn run(region_path: PathBuf, fasta_path: PathBuf, bam_path: PathBuf) {
-    let fa_reader = faidx::Reader::from_path(&fasta_path).unwrap();
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    region_path: Option<PathBuf>,
+    region_strs: Vec<String>,
+    alias: ChromAlias,
+    fasta_path: PathBuf,
+    bam_path: PathBuf,
+    output_path: Option<PathBuf>,
+    read_filter: ReadFilter,
+    hts_threads: usize,
+    auto_index: bool,
+    strict: bool,
+) -> Result<()> {
+    hts::check_indexes(
+        Some(&fasta_path),
+        std::slice::from_ref(&bam_path),
+        auto_index,
+        hts_threads,
+    )?;
+    let fa_reader = faidx::Reader::from_path(&fasta_path)
+        .map_err(|e| Error::Input(format!("Failed to open {:?}: {}", fasta_path, e)))?;
 
-    let mut pos_reader = csv::ReaderBuilder::new()
-        .has_headers(false)
-        .delimiter(b'\t')
-        .from_path(&region_path)
-        .unwrap();
+    let mut regions = match &region_path {
+        Some(region_path) => region::read_bed(region_path)?,
+        None => Vec::new(),
+    };
+    let fai_len = region::fai_lengths(&fasta_path)?;
+    for s in &region_strs {
+        regions.push(region::parse_region_str(s, &fai_len, &alias)?);
+    }
+    let mut regions = region::clip_regions(regions, &fai_len, &alias, strict)?;
+    let output = Output::create(output_path.as_deref(), hts_threads)?;
+    // sorted and without duplicated sites to be indexed
+    if output.is_indexed() {
+        regions = region::merge_regions(regions, false);
+    }
 
-    let mut bam_reader = bam::IndexedReader::from_path(&bam_path).unwrap();
+    let thread_pool = match hts_threads {
+        0 => None,
+        n => Some(hts::ThreadPool::new(n)?),
+    };
+    let mut bam_reader = hts::open_bam(&bam_path, Some(&fasta_path), thread_pool.as_ref())?;
     let bam_header = bam_reader.header().clone();
 
     let mut csv_writer = csv::WriterBuilder::new()
         .delimiter(b'\t')
-        .from_writer(io::BufWriter::new(io::stdout()));
+        .from_writer(output);
 
-    for (_i, record) in pos_reader.deserialize().enumerate() {
-        let record: PosRecord = record.unwrap();
-
-        let tid = bam_header.tid(record.chrom.as_bytes()).unwrap();
-        let start = record.start as u32;
-        let end = record.end as u32;
-        bam_reader.fetch((tid, start, end)).unwrap();
+    // reads excluded by MAPQ, each counted at its first site in a region
+    let mut mapq_excluded: u64 = 0;
+    let mut missing: Vec<&str> = Vec::new();
+    for record in &regions {
+        // contig missing from the bam file, zero coverage
+        let tid = match alias
+            .resolve(&record.chrom, |c| bam_header.tid(c.as_bytes()).is_some())
+            .and_then(|c| bam_header.tid(c.as_bytes()))
+        {
+            Some(tid) => tid,
+            None if strict => {
+                return Err(Error::Contig(format!(
+                    "Contig `{}` is not in {:?}.",
+                    record.chrom, bam_path
+                )));
+            }
+            None => {
+                if !missing.contains(&record.chrom.as_str()) {
+                    eprintln!(
+                        "Contig `{}` is not in {:?}, counted as zero coverage.",
+                        record.chrom, bam_path
+                    );
+                    missing.push(&record.chrom);
+                }
+                continue;
+            }
+        };
+        let start = record.start;
+        let end = record.end;
+        let read_err = |e| Error::Input(format!("Failed to read {:?}: {}", bam_path, e));
+        bam_reader.fetch((tid, start, end)).map_err(read_err)?;
 
         // pileup over all covered sites
         for p in bam_reader.pileup() {
             let mut ref_count = 0;
             let mut alt_count = 0;
             let mut indel_count = 0;
+            let mut depth = 0;
 
-            let pileup = p.unwrap();
+            let pileup = p.map_err(read_err)?;
+            // reads overlapping the region cover sites beyond it
+            if pileup.pos() < start || pileup.pos() >= end {
+                continue;
+            }
             let ref_pos = pileup.pos() as usize;
             let r = fa_reader
                 .fetch_seq_string(&record.chrom, ref_pos, ref_pos)
-                .unwrap();
+                .map_err(|e| Error::Input(format!("Failed to read {:?}: {}", fasta_path, e)))?;
 
             for alignment in pileup.alignments() {
+                let read = alignment.record();
+                if !read_filter.pass_flags(&read) {
+                    continue;
+                }
+                if !read_filter.pass_mapq(&read) {
+                    if pileup.pos() == (read.pos() as u32).max(start) {
+                        mapq_excluded += 1;
+                    }
+                    continue;
+                }
+                if let Some(qpos) = alignment.qpos() {
+                    if read_filter.trims_ends() && !read_filter.pass_qpos(&read, qpos) {
+                        continue;
+                    }
+                }
+                depth += 1;
                 if !alignment.is_del() && !alignment.is_refskip() {
                     let base = alignment.record().seq()[alignment.qpos().unwrap()] as char;
                     if base.to_string().to_uppercase() != r {
@@ -64,17 +136,29 @@ pub fn run(region_path: PathBuf, fasta_path: PathBuf, bam_path: PathBuf) {
                 }
             }
 
-            csv_writer
-                .serialize((
-                    &record.chrom,
-                    ref_pos,
-                    r,
-                    pileup.depth(),
-                    ref_count,
-                    alt_count,
-                    indel_count,
-                ))
-                .unwrap();
+            csv_writer.serialize((
+                &record.chrom,
+                ref_pos,
+                r,
+                depth,
+                ref_count,
+                alt_count,
+                indel_count,
+            ))?;
         }
     }
+    let output = csv_writer
+        .into_inner()
+        .map_err(|e| io::Error::new(e.error().kind(), e.error().to_string()))?;
+    // positions of count are 0-based
+    output.finish(0, true)?;
+
+    if read_filter.min_mapq > 0 || read_filter.unique_only {
+        eprintln!(
+            "{}\t{} reads excluded by MAPQ filter",
+            bam_path.display(),
+            mapq_excluded
+        );
+    }
+    Ok(())
 }
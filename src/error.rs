@@ -0,0 +1,67 @@
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// invalid combination of options
+    Param(String),
+    /// input file can't be opened, read or parsed
+    Input(String),
+    /// index of a bam/cram/fasta file is missing or can't be built
+    Index(String),
+    /// contig missing from an input file, or of different length
+    Contig(String),
+    /// output can't be written
+    Write(io::Error),
+}
+
+impl Error {
+    /// Exit code of the process, distinct for each kind of error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            // as if killed by SIGPIPE
+            Error::Write(e) if e.kind() == io::ErrorKind::BrokenPipe => 141,
+            Error::Param(_) => 2,
+            Error::Input(_) => 3,
+            Error::Index(_) => 4,
+            Error::Contig(_) => 5,
+            Error::Write(_) => 6,
+        }
+    }
+
+    /// Output is closed by the reader, e.g. `mpileup ... | head`.
+    pub fn is_broken_pipe(&self) -> bool {
+        matches!(self, Error::Write(e) if e.kind() == io::ErrorKind::BrokenPipe)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Param(msg) | Error::Input(msg) | Error::Index(msg) | Error::Contig(msg) => {
+                write!(f, "{}", msg)
+            }
+            Error::Write(e) => write!(f, "Failed to write output: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// `?` on writes, reads are mapped to `Error::Input` explicitly
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Write(e)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Self {
+        match e.into_kind() {
+            csv::ErrorKind::Io(e) => Error::Write(e),
+            kind => Error::Write(io::Error::other(format!("{:?}", kind))),
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use rust_htslib::bam;
+
+// flag names as used by `samtools flags`
+const FLAG_NAMES: [(&str, u16); 12] = [
+    ("PAIRED", 0x1),
+    ("PROPER_PAIR", 0x2),
+    ("UNMAP", 0x4),
+    ("MUNMAP", 0x8),
+    ("REVERSE", 0x10),
+    ("MREVERSE", 0x20),
+    ("READ1", 0x40),
+    ("READ2", 0x80),
+    ("SECONDARY", 0x100),
+    ("QCFAIL", 0x200),
+    ("DUP", 0x400),
+    ("SUPPLEMENTARY", 0x800),
+];
+
+pub const DEFAULT_EXCL_FLAGS: &str = "UNMAP,SECONDARY,QCFAIL,DUP";
+
+/// Parse a SAM flag given as a decimal or hex number, or as comma-separated names
+/// (e.g. `PAIRED,PROPER_PAIR`).
+pub fn parse_flags(s: &str) -> Result<u16, String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(0);
+    }
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        return u16::from_str_radix(hex, 16).map_err(|_| format!("invalid sam flag: {s}"));
+    }
+    if let Ok(v) = s.parse::<u16>() {
+        return Ok(v);
+    }
+    let mut flags = 0;
+    for name in s.split(',') {
+        let name = name.trim().to_uppercase();
+        match FLAG_NAMES.iter().find(|(n, _)| *n == name) {
+            Some((_, v)) => flags |= v,
+            None => return Err(format!("unknown sam flag name: {name}")),
+        }
+    }
+    Ok(flags)
+}
+
+/// Read and base level filters applied before anything is counted.
+pub struct ReadFilter {
+    /// keep reads with any of these bits set (0 keeps everything)
+    pub incl_flags: u16,
+    /// drop reads with any of these bits set
+    pub excl_flags: u16,
+    /// drop reads with mapping quality below this value
+    pub min_mapq: u8,
+    /// drop multimappers (MAPQ 0), but keep MAPQ 255 (unique reads of STAR)
+    pub unique_only: bool,
+    /// ignore bases within N bp of the 5' end of read, in read orientation
+    pub trim_5p: usize,
+    /// ignore bases within N bp of the 3' end of read, in read orientation
+    pub trim_3p: usize,
+    /// ignore bases within N bp of a soft clip
+    pub clip_dist: usize,
+}
+
+impl ReadFilter {
+    pub fn pass_flags(&self, record: &bam::Record) -> bool {
+        let flags = record.flags();
+        if self.incl_flags != 0 && flags & self.incl_flags == 0 {
+            return false;
+        }
+        flags & self.excl_flags == 0
+    }
+
+    pub fn pass_mapq(&self, record: &bam::Record) -> bool {
+        let mapq = record.mapq();
+        if self.unique_only && mapq == 0 {
+            return false;
+        }
+        mapq >= self.min_mapq
+    }
+
+    pub fn trims_ends(&self) -> bool {
+        self.trim_5p > 0 || self.trim_3p > 0 || self.clip_dist > 0
+    }
+
+    /// Check whether the base at `qpos` (position on the stored sequence) survives end trimming.
+    pub fn pass_qpos(&self, record: &bam::Record, qpos: usize) -> bool {
+        let read_len = record.seq_len();
+        // distance to the left and right end of the stored sequence
+        let left = qpos;
+        let right = read_len - 1 - qpos;
+        let (from_5p, from_3p) = if record.is_reverse() {
+            (right, left)
+        } else {
+            (left, right)
+        };
+        if from_5p < self.trim_5p || from_3p < self.trim_3p {
+            return false;
+        }
+        if self.clip_dist > 0 {
+            let cigar = record.cigar();
+            let leading = cigar.leading_softclips() as usize;
+            let trailing = cigar.trailing_softclips() as usize;
+            if leading > 0 && left < leading + self.clip_dist {
+                return false;
+            }
+            if trailing > 0 && right < trailing + self.clip_dist {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Cigar, CigarString, Record};
+
+    fn filter(trim_5p: usize, trim_3p: usize, clip_dist: usize) -> ReadFilter {
+        ReadFilter {
+            incl_flags: 0,
+            excl_flags: 0,
+            min_mapq: 0,
+            unique_only: false,
+            trim_5p,
+            trim_3p,
+            clip_dist,
+        }
+    }
+
+    fn record(cigar: Vec<Cigar>, flags: u16) -> Record {
+        let cigar = CigarString(cigar);
+        let len = cigar
+            .iter()
+            .map(|c| match c {
+                Cigar::Match(l) | Cigar::Ins(l) | Cigar::SoftClip(l) => *l as usize,
+                _ => 0,
+            })
+            .sum();
+        let mut record = Record::new();
+        record.set(b"r1", Some(&cigar), &vec![b'A'; len], &vec![30; len]);
+        record.set_pos(100);
+        record.set_flags(flags);
+        record
+    }
+
+    #[test]
+    fn flags_as_numbers_or_names() {
+        assert_eq!(parse_flags("0x904"), Ok(0x904));
+        assert_eq!(parse_flags("0X10"), Ok(0x10));
+        assert_eq!(parse_flags("1796"), Ok(1796));
+        assert_eq!(parse_flags(""), Ok(0));
+        assert_eq!(parse_flags("PAIRED,proper_pair"), Ok(0x3));
+        assert_eq!(parse_flags(DEFAULT_EXCL_FLAGS), Ok(0x704));
+        assert!(parse_flags("0xZZ").is_err());
+        assert!(parse_flags("70000").is_err());
+        assert_eq!(
+            parse_flags("PAIRED,MATE"),
+            Err("unknown sam flag name: MATE".to_string())
+        );
+    }
+
+    #[test]
+    fn trim_ends_of_forward_read() {
+        let rec = record(vec![Cigar::Match(10)], 0);
+        let f = filter(2, 3, 0);
+        let pass = (0..10).map(|q| f.pass_qpos(&rec, q)).collect::<Vec<_>>();
+        assert_eq!(
+            pass,
+            [false, false, true, true, true, true, true, false, false, false]
+        );
+    }
+
+    #[test]
+    fn trim_ends_of_reverse_read() {
+        // the 5' end is the last stored base
+        let rec = record(vec![Cigar::Match(10)], 0x10);
+        let f = filter(2, 3, 0);
+        let pass = (0..10).map(|q| f.pass_qpos(&rec, q)).collect::<Vec<_>>();
+        assert_eq!(
+            pass,
+            [false, false, false, true, true, true, true, true, false, false]
+        );
+    }
+
+    #[test]
+    fn distance_to_soft_clips() {
+        let f = filter(0, 0, 2);
+        // clips are on the stored sequence whatever the strand
+        for flags in [0, 0x10] {
+            let rec = record(
+                vec![Cigar::SoftClip(3), Cigar::Match(10), Cigar::SoftClip(2)],
+                flags,
+            );
+            let pass = (3..13).map(|q| f.pass_qpos(&rec, q)).collect::<Vec<_>>();
+            assert_eq!(
+                pass,
+                [false, false, true, true, true, true, true, true, false, false]
+            );
+        }
+        // nothing trimmed without clips
+        let rec = record(vec![Cigar::Match(10)], 0);
+        assert!((0..10).all(|q| f.pass_qpos(&rec, q)));
+    }
+}
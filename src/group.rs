@@ -0,0 +1,73 @@
+use crate::error::{Error, Result};
+use rust_htslib::bam::{self, record::Aux};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+pub const UNASSIGNED: &str = "unassigned";
+
+/// Split the reads of each bam file into groups by the value of a tag, e.g. cell barcode.
+pub struct TagGroup {
+    pub tag: [u8; 2],
+    values: Vec<String>,
+    index: HashMap<Vec<u8>, usize>,
+    /// collect reads without tag (or not in whitelist) into an extra group
+    keep_unassigned: bool,
+}
+
+impl TagGroup {
+    pub fn new(tag: [u8; 2], values: Vec<String>, keep_unassigned: bool) -> Self {
+        let index = values
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (v.as_bytes().to_vec(), i))
+            .collect();
+        TagGroup {
+            tag,
+            values,
+            index,
+            keep_unassigned,
+        }
+    }
+
+    /// Read tag values from a whitelist file, one value per line.
+    pub fn from_whitelist(tag: [u8; 2], path: &Path, keep_unassigned: bool) -> Result<Self> {
+        let values = fs::read_to_string(path)
+            .map_err(|e| Error::Input(format!("Failed to read {:?}: {}", path, e)))?
+            .lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect();
+        Ok(TagGroup::new(tag, values, keep_unassigned))
+    }
+
+    pub fn value<'a>(&self, record: &'a bam::Record) -> Option<&'a str> {
+        match record.aux(&self.tag) {
+            Ok(Aux::String(s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn n_groups(&self) -> usize {
+        self.values.len() + usize::from(self.keep_unassigned)
+    }
+
+    pub fn group(&self, record: &bam::Record) -> Option<usize> {
+        match self
+            .value(record)
+            .and_then(|v| self.index.get(v.as_bytes()))
+        {
+            Some(g) => Some(*g),
+            None if self.keep_unassigned => Some(self.values.len()),
+            None => None,
+        }
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = self.values.iter().map(|v| v.as_str()).collect::<Vec<_>>();
+        if self.keep_unassigned {
+            names.push(UNASSIGNED);
+        }
+        names
+    }
+}
@@ -0,0 +1,188 @@
+use crate::error::{Error, Result};
+use rust_htslib::bam::{self, Read};
+use rust_htslib::{htslib, utils};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// longest contig a bai index can hold, csi is needed beyond it
+const BAI_MAX_LEN: u64 = (1 << 29) - 1;
+
+/// Pool of htslib threads to decompress bam/cram, shared by the readers of all worker threads so
+/// that the number of threads doesn't grow with the number of files.
+pub struct ThreadPool {
+    inner: htslib::htsThreadPool,
+}
+
+// the pool of htslib is made to be used by many files from many threads
+unsafe impl Send for ThreadPool {}
+unsafe impl Sync for ThreadPool {}
+
+impl ThreadPool {
+    pub fn new(n_threads: usize) -> Result<Self> {
+        let pool = unsafe { htslib::hts_tpool_init(n_threads as i32) };
+        if pool.is_null() {
+            return Err(Error::Param(format!(
+                "Failed to start {} htslib threads.",
+                n_threads
+            )));
+        }
+        Ok(ThreadPool {
+            inner: htslib::htsThreadPool {
+                pool,
+                qsize: n_threads as i32 * 2,
+            },
+        })
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        unsafe { htslib::hts_tpool_destroy(self.inner.pool) };
+    }
+}
+
+/// Open an indexed bam or cram file. The index (`.bai`, `.csi` or `.crai`) is looked up next to
+/// the file, and the reference fasta is used to decode cram (ignored for bam). The pool must
+/// outlive the reader.
+pub fn open_bam(
+    bam_path: &Path,
+    fasta_path: Option<&Path>,
+    thread_pool: Option<&ThreadPool>,
+) -> Result<bam::IndexedReader> {
+    let err = |e| Error::Input(format!("Failed to open {:?}: {}", bam_path, e));
+    let mut bam_reader = bam::IndexedReader::from_path(bam_path).map_err(err)?;
+    if let Some(fasta_path) = fasta_path {
+        bam_reader.set_reference(fasta_path).map_err(err)?;
+    }
+    if let Some(pool) = thread_pool {
+        let mut inner = pool.inner;
+        if unsafe { htslib::hts_set_thread_pool(bam_reader.htsfile(), &mut inner) } != 0 {
+            return Err(Error::Input(format!(
+                "Failed to set htslib threads of {:?}.",
+                bam_path
+            )));
+        }
+    }
+    Ok(bam_reader)
+}
+
+/// Whether the bam/cram files opened by each worker thread (and the fasta) fit in the limit of
+/// open files (`ulimit -n`), raising the soft limit up to the hard one if needed.
+pub fn fit_open_files(n_bams: usize, n_jobs: usize) -> bool {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return true;
+    }
+    // and a few for stdio, output and index files
+    let needed = (n_jobs * (n_bams + 1) + 16) as libc::rlim_t;
+    if needed <= limit.rlim_cur {
+        return true;
+    }
+    limit.rlim_cur = needed.min(limit.rlim_max);
+    let raised = unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } == 0;
+    raised && needed <= limit.rlim_max
+}
+
+/// Check the indexes of the fasta and bam/cram files before a run. A missing fasta index is always
+/// built, as htslib does. Missing bam/cram and stale (older than the indexed file) indexes are
+/// built when `build` is set, otherwise missing ones are errors and stale ones are warned.
+pub fn check_indexes(
+    fasta_path: Option<&Path>,
+    bam_path_list: &[PathBuf],
+    build: bool,
+    hts_threads: usize,
+) -> Result<()> {
+    if let Some(fasta_path) = fasta_path {
+        check_fasta_index(fasta_path, build)?;
+    }
+    for bam_path in bam_path_list {
+        check_bam_index(bam_path, build, hts_threads)?;
+    }
+    Ok(())
+}
+
+fn check_fasta_index(fasta_path: &Path, build: bool) -> Result<()> {
+    let fai_path = with_suffix(fasta_path, "fai");
+    if fai_path.is_file() && !need_build(&fai_path, fasta_path, build) {
+        return Ok(());
+    }
+    eprintln!("Building index {:?}...", fai_path);
+    let c_path = utils::path_to_cstring(&fasta_path)
+        .ok_or_else(|| Error::Input(format!("Invalid path {:?}.", fasta_path)))?;
+    if unsafe { htslib::fai_build(c_path.as_ptr()) } != 0 {
+        return Err(Error::Index(format!(
+            "Failed to build index of {:?}.",
+            fasta_path
+        )));
+    }
+    Ok(())
+}
+
+fn check_bam_index(bam_path: &Path, build: bool, hts_threads: usize) -> Result<()> {
+    let is_cram = bam_path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("cram"));
+    // in the order htslib looks for them
+    let exts: &[&str] = if is_cram { &["crai"] } else { &["csi", "bai"] };
+    let index_path = exts
+        .iter()
+        .flat_map(|ext| [with_suffix(bam_path, ext), bam_path.with_extension(ext)])
+        .find(|p| p.is_file());
+    let index_path = match index_path {
+        Some(p) if !need_build(&p, bam_path, build) => return Ok(()),
+        Some(p) => p,
+        None if build => with_suffix(bam_path, exts[exts.len() - 1]),
+        None => {
+            return Err(Error::Index(format!(
+                "Index of {:?} is not found, use `--auto-index` to build it.",
+                bam_path
+            )))
+        }
+    };
+
+    let use_csi = !is_cram
+        && (index_path.extension().is_some_and(|e| e == "csi") || has_long_contig(bam_path)?);
+    let (index_path, index_type) = if use_csi {
+        (index_path.with_extension("csi"), bam::index::Type::Csi(14))
+    } else {
+        (index_path, bam::index::Type::Bai)
+    };
+    eprintln!("Building index {:?}...", index_path);
+    bam::index::build(bam_path, Some(&index_path), index_type, hts_threads as u32)
+        .map_err(|e| Error::Index(format!("Failed to build index of {:?}: {}", bam_path, e)))
+}
+
+// whether any contig is too long for bai
+fn has_long_contig(bam_path: &Path) -> Result<bool> {
+    let bam_reader = bam::Reader::from_path(bam_path)
+        .map_err(|e| Error::Input(format!("Failed to open {:?}: {}", bam_path, e)))?;
+    let header = bam_reader.header();
+    Ok((0..header.target_count()).any(|tid| header.target_len(tid).unwrap_or(0) > BAI_MAX_LEN))
+}
+
+// whether an existing index is to be rebuilt
+fn need_build(index_path: &Path, data_path: &Path, build: bool) -> bool {
+    let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+    let stale = matches!(
+        (modified(index_path), modified(data_path)),
+        (Some(i), Some(d)) if i < d
+    );
+    if stale && !build {
+        eprintln!(
+            "Index {:?} is older than {:?}, use `--auto-index` to rebuild it.",
+            index_path, data_path
+        );
+    }
+    stale && build
+}
+
+// `x.bam` -> `x.bam.bai`
+fn with_suffix(path: &Path, ext: &str) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(".");
+    p.push(ext);
+    p.into()
+}
@@ -1,7 +1,21 @@
+mod alias;
 mod base;
+mod columnar;
 mod count;
+mod error;
+mod filter;
+mod group;
+mod hts;
+mod output;
+mod region;
+mod sample;
+mod samtools;
+mod strand;
+mod strandness;
+mod umi;
+mod vcf;
 
-use clap::Parser;
+use clap::{Args, Parser};
 use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
@@ -18,25 +32,72 @@ struct Opts {
 enum SubCommand {
     Base(Base),
     Count(Count),
+    Strandness(Strandness),
 }
 
 #[derive(Parser)]
 struct Base {
     #[clap(short = 'g', long = "count-indel", help = "Count indel?")]
     indel: bool,
-    #[clap(short = 't', long = "target", help = "input bed file..", validator = file_path_validation)]
-    bed: PathBuf,
+    #[clap(short = 't', long = "target", help = "input bed file.. (default: whole genome)", validator = file_path_validation)]
+    bed: Option<PathBuf>,
+    #[clap(
+        short = 'R',
+        long = "region",
+        help = "Region(s) like chr, chr:start or chr:start-end (1-based, inclusive)",
+        takes_value = true,
+        multiple_values = true
+    )]
+    regions: Vec<String>,
+    #[clap(
+        long = "include-contig",
+        help = "Only report contigs matching the name or regex",
+        parse(try_from_str = contig_regex)
+    )]
+    include_contig: Option<regex::Regex>,
+    #[clap(
+        long = "exclude-contig",
+        help = "Skip contigs matching the name or regex (e.g. 'chrM|.*_decoy')",
+        parse(try_from_str = contig_regex)
+    )]
+    exclude_contig: Option<regex::Regex>,
+    #[clap(
+        long = "chrom-alias",
+        help = "Table of contig aliases, all names of a contig per line (like UCSC chromAlias.txt). Names with or without `chr` are matched anyway",
+        validator = file_path_validation
+    )]
+    chrom_alias: Option<PathBuf>,
     #[clap(short = 'r', long = "reference", help = "input fa file..", validator = file_path_validation)]
     fa: PathBuf,
     #[clap(
         short = 'i',
         long = "input",
-        help = "input bam files..",
-        required = true,
+        help = "input bam/cram files..",
+        required_unless_present = "sample-sheet",
+        conflicts_with = "sample-sheet",
         parse(from_os_str),
         takes_value = true, multiple_values = true, validator = file_path_validation,
     )]
     bam: Vec<PathBuf>,
+    #[clap(
+        long = "sample-sheet",
+        help = "Tab-separated file of samples: name, bam/cram path, optional group and optional library type. (name defaults to `SM` of `@RG`)",
+        validator = file_path_validation
+    )]
+    sample_sheet: Option<PathBuf>,
+    #[clap(
+        short = 'o',
+        long = "output",
+        help = "Output file instead of stdout. `.gz` (or `.bgz`) is bgzip compressed and tabix indexed"
+    )]
+    output: Option<PathBuf>,
+    #[clap(
+        long = "output-format",
+        visible_alias = "format",
+        arg_enum,
+        help = "Output format, told by the extension of output file by default (`.vcf`, `.vcf.gz`, `.bcf`, `.parquet`, `.arrow`, otherwise tsv)"
+    )]
+    output_format: Option<output::OutputFormat>,
     #[clap(
         short = 'd',
         long = "min-depth",
@@ -58,12 +119,91 @@ struct Base {
         default_value = "0"
     )]
     qual: u8,
+    #[clap(flatten)]
+    filter: FilterOpts,
+    #[clap(
+        long = "umi-sep",
+        help = "Remove duplicates by UMI, taken from the qname suffix after the last separator",
+        conflicts_with_all = &["umi-regex", "umi-tag"]
+    )]
+    umi_sep: Option<char>,
+    #[clap(
+        long = "umi-regex",
+        help = "Remove duplicates by UMI, taken from the first capture group of the regex on qname",
+        conflicts_with = "umi-tag",
+        parse(try_from_str = regex::bytes::Regex::new)
+    )]
+    umi_regex: Option<regex::bytes::Regex>,
+    #[clap(
+        long = "umi-tag",
+        help = "Remove duplicates by UMI, taken from the tag (e.g. RX, UB)",
+        validator = tag_validation
+    )]
+    umi_tag: Option<String>,
+    #[clap(
+        long = "umi-dist",
+        help = "Max edit distance between UMIs of the same molecule",
+        default_value = "0"
+    )]
+    umi_dist: usize,
+    #[clap(
+        long = "group-by-tag",
+        help = "Split counts of each bam file into groups by the tag (e.g. CB for cell barcode)",
+        validator = tag_validation
+    )]
+    group_tag: Option<String>,
+    #[clap(
+        long = "tag-whitelist",
+        help = "File of tag values to report, one per line. (default: all values found in target, needs `--target`)",
+        requires = "group-tag",
+        validator = file_path_validation
+    )]
+    tag_whitelist: Option<PathBuf>,
+    #[clap(
+        long = "keep-unassigned",
+        help = "Collect reads without tag (or not in whitelist) into an `unassigned` group",
+        requires = "group-tag"
+    )]
+    keep_unassigned: bool,
+    #[clap(
+        long = "max-tag-values",
+        help = "Stop if more tag values than this are found in target without `--tag-whitelist`",
+        requires = "group-tag",
+        default_value = "1000"
+    )]
+    max_tag_values: usize,
     #[clap(
         short = 'H',
         long = "headless",
         help = "Write without header in the output"
     )]
     headless: bool,
+    #[clap(
+        long = "long",
+        help = "Write one row per site and sample, with a named column for each count"
+    )]
+    long: bool,
+    #[clap(
+        long = "field-header",
+        help = "Write a `#Fields` line of the order of the comma-separated counts above the header",
+        conflicts_with_all = &["long", "headless"]
+    )]
+    field_header: bool,
+    #[clap(
+        long = "output-mapq",
+        help = "Write a column of mapping qualities of reads in samtools format (like `samtools mpileup -s`)"
+    )]
+    output_mapq: bool,
+    #[clap(
+        long = "output-bp",
+        help = "Write a column of base positions in reads in samtools format (like `samtools mpileup -O`)"
+    )]
+    output_pos: bool,
+    #[clap(
+        long = "all-sites",
+        help = "Write sites without any read in samtools format too (like `samtools mpileup -a`)"
+    )]
+    all_sites: bool,
     #[clap(
         short = 'S',
         long = "strandless",
@@ -76,6 +216,23 @@ struct Base {
         help = "Split counts into different rows by strand"
     )]
     bystrand: bool,
+    #[clap(
+        long = "target-strand",
+        help = "Split counts by strand, but only report the strand of target region (6th column of bed)"
+    )]
+    target_strand: bool,
+    #[clap(
+        long = "library-type",
+        arg_enum,
+        default_value = "fr-secondstrand",
+        help = "How to tell the strand of transcript from reads (unless set in sample sheet)"
+    )]
+    library_type: strand::LibraryType,
+    #[clap(
+        long = "keep-overlap",
+        help = "Do not merge overlapping regions in target, so that overlapped sites are reported repeatedly"
+    )]
+    keep_overlap: bool,
     #[clap(
         short = 'c',
         long = "--chunk-size",
@@ -90,6 +247,22 @@ struct Base {
         help = "Start the job in multiple threads"
     )]
     njobs: usize,
+    #[clap(
+        long = "hts-threads",
+        default_value = "0",
+        help = "Extra htslib threads to decompress bam files, shared by all files"
+    )]
+    hts_threads: usize,
+    #[clap(
+        long = "auto-index",
+        help = "Build missing bam/cram indexes and rebuild outdated ones (csi for contigs over 512 Mb)"
+    )]
+    auto_index: bool,
+    #[clap(
+        long = "strict",
+        help = "Exit with error on contigs missing from bam/fasta or regions beyond contig end, instead of warning"
+    )]
+    strict: bool,
     #[clap(
         short = 'l',
         long = "--log-type",
@@ -103,12 +276,173 @@ struct Base {
 struct Count {
     #[clap(short, long, help = "debug")]
     debug: bool,
-    #[clap(short = 't', long = "target", help = "input bed file..")]
-    bed: PathBuf,
+    #[clap(
+        short = 't',
+        long = "target",
+        help = "input bed file..",
+        required_unless_present = "regions"
+    )]
+    bed: Option<PathBuf>,
+    #[clap(
+        short = 'R',
+        long = "region",
+        help = "Region(s) like chr, chr:start or chr:start-end (1-based, inclusive)",
+        takes_value = true,
+        multiple_values = true
+    )]
+    regions: Vec<String>,
+    #[clap(
+        long = "chrom-alias",
+        help = "Table of contig aliases, all names of a contig per line (like UCSC chromAlias.txt). Names with or without `chr` are matched anyway",
+        validator = file_path_validation
+    )]
+    chrom_alias: Option<PathBuf>,
     #[clap(short = 'r', long = "reference", help = "input fa file..")]
     fa: PathBuf,
-    #[clap(short = 'i', long = "input", help = "input bam file..")]
+    #[clap(short = 'i', long = "input", help = "input bam/cram file..")]
     bam: PathBuf,
+    #[clap(
+        short = 'o',
+        long = "output",
+        help = "Output file instead of stdout. `.gz` (or `.bgz`) is bgzip compressed and tabix indexed"
+    )]
+    output: Option<PathBuf>,
+    #[clap(
+        long = "hts-threads",
+        default_value = "0",
+        help = "Extra htslib threads to decompress bam files, shared by all files"
+    )]
+    hts_threads: usize,
+    #[clap(
+        long = "auto-index",
+        help = "Build missing bam/cram indexes and rebuild outdated ones (csi for contigs over 512 Mb)"
+    )]
+    auto_index: bool,
+    #[clap(
+        long = "strict",
+        help = "Exit with error on contigs missing from bam/fasta or regions beyond contig end, instead of warning"
+    )]
+    strict: bool,
+    #[clap(flatten)]
+    filter: FilterOpts,
+}
+
+#[derive(Parser)]
+struct Strandness {
+    #[clap(
+        short = 't',
+        long = "target",
+        help = "Stranded annotation in bed6 or gtf format (exons are used)",
+        validator = file_path_validation
+    )]
+    annotation: PathBuf,
+    #[clap(short = 'r', long = "reference", help = "input fa file, required to decode cram..", validator = file_path_validation)]
+    fa: Option<PathBuf>,
+    #[clap(
+        short = 'i',
+        long = "input",
+        help = "input bam/cram files..",
+        required = true,
+        parse(from_os_str),
+        takes_value = true, multiple_values = true, validator = file_path_validation,
+    )]
+    bam: Vec<PathBuf>,
+    #[clap(
+        short = 'n',
+        long = "sample-size",
+        help = "Number of reads sampled from each bam file",
+        default_value = "200000"
+    )]
+    sample_size: usize,
+    #[clap(
+        long = "auto-index",
+        help = "Build missing bam/cram indexes and rebuild outdated ones (csi for contigs over 512 Mb)"
+    )]
+    auto_index: bool,
+    #[clap(flatten)]
+    filter: FilterOpts,
+}
+
+#[derive(Args)]
+struct FilterOpts {
+    #[clap(
+        long = "incl-flags",
+        help = "Required flags: keep reads with any of the mask bits set. (number or names, e.g. PAIRED,PROPER_PAIR)",
+        default_value = "0",
+        parse(try_from_str = filter::parse_flags)
+    )]
+    incl_flags: u16,
+    #[clap(
+        long = "excl-flags",
+        help = "Filter flags: skip reads with any of the mask bits set. (number or names)",
+        default_value = filter::DEFAULT_EXCL_FLAGS,
+        parse(try_from_str = filter::parse_flags)
+    )]
+    excl_flags: u16,
+    #[clap(
+        short = 'Q',
+        long = "min-mapq",
+        help = "Set min mapping quality for read. (greater or equal to)",
+        default_value = "0"
+    )]
+    min_mapq: u8,
+    #[clap(
+        short = 'u',
+        long = "unique-only",
+        help = "Ignore multi-mapped reads (MAPQ 0). MAPQ 255 of unique reads by STAR is kept"
+    )]
+    unique_only: bool,
+    #[clap(
+        long = "trim-5p",
+        help = "Ignore bases within N bp of the 5' end of read (in read orientation)",
+        default_value = "0"
+    )]
+    trim_5p: usize,
+    #[clap(
+        long = "trim-3p",
+        help = "Ignore bases within N bp of the 3' end of read (in read orientation)",
+        default_value = "0"
+    )]
+    trim_3p: usize,
+    #[clap(
+        long = "clip-dist",
+        help = "Ignore bases within N bp of a soft clip",
+        default_value = "0"
+    )]
+    clip_dist: usize,
+}
+
+impl FilterOpts {
+    fn read_filter(&self) -> filter::ReadFilter {
+        filter::ReadFilter {
+            incl_flags: self.incl_flags,
+            excl_flags: self.excl_flags,
+            min_mapq: self.min_mapq,
+            unique_only: self.unique_only,
+            trim_5p: self.trim_5p,
+            trim_3p: self.trim_3p,
+            clip_dist: self.clip_dist,
+        }
+    }
+}
+
+impl Base {
+    fn umi_dedup(&self) -> Option<umi::UmiDedup> {
+        let source = if let Some(sep) = self.umi_sep {
+            umi::UmiSource::QnameSuffix(sep as u8)
+        } else if let Some(re) = &self.umi_regex {
+            umi::UmiSource::QnameRegex(re.clone())
+        } else if let Some(tag) = &self.umi_tag {
+            let tag = tag.as_bytes();
+            umi::UmiSource::Tag([tag[0], tag[1]])
+        } else {
+            return None;
+        };
+        Some(umi::UmiDedup {
+            source,
+            max_dist: self.umi_dist,
+        })
+    }
 }
 
 impl SubCommand {}
@@ -125,29 +459,93 @@ fn main() {
         _ => println!("Don't be ridiculous"),
     }
 
+    if let Err(e) = run(opts.subcmd) {
+        // output closed by the reader (e.g. `| head`), nothing to report
+        if !e.is_broken_pipe() {
+            eprintln!("{}", e);
+        }
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run(subcmd: SubCommand) -> error::Result<()> {
     // You can handle information about subcommands by requesting their matches by name
     // (as below), requesting just the name used, or both at the same time
-    match opts.subcmd {
+    match subcmd {
         SubCommand::Base(o) => {
+            let umi_dedup = o.umi_dedup();
+            let output_format = o
+                .output_format
+                .unwrap_or_else(|| output::OutputFormat::from_path(o.output.as_deref()));
+            let samples = match &o.sample_sheet {
+                Some(pth) => sample::read_sample_sheet(pth, o.library_type)?,
+                None => sample::from_paths(o.bam, o.library_type),
+            };
             base::run(
                 o.bed,
+                o.regions,
+                o.include_contig,
+                o.exclude_contig,
+                chrom_alias(o.chrom_alias.as_deref())?,
                 o.fa,
-                o.bam,
+                samples,
+                o.output,
+                output_format,
+                o.hts_threads,
+                o.auto_index,
+                o.strict,
                 o.min_depth,
                 o.mean_depth,
                 o.qual,
+                o.filter.read_filter(),
+                umi_dedup,
+                o.group_tag.map(|t| [t.as_bytes()[0], t.as_bytes()[1]]),
+                o.tag_whitelist,
+                o.keep_unassigned,
+                o.max_tag_values,
                 o.indel,
                 o.headless,
+                o.long,
+                o.field_header,
+                o.output_mapq,
+                o.output_pos,
+                o.all_sites,
                 o.strandless,
                 o.bystrand,
+                o.target_strand,
+                o.keep_overlap,
                 o.chunk,
                 o.njobs,
                 o.logtype,
-            );
-        }
-        SubCommand::Count(o) => {
-            count::run(o.bed, o.fa, o.bam);
+            )
         }
+        SubCommand::Count(o) => count::run(
+            o.bed,
+            o.regions,
+            chrom_alias(o.chrom_alias.as_deref())?,
+            o.fa,
+            o.bam,
+            o.output,
+            o.filter.read_filter(),
+            o.hts_threads,
+            o.auto_index,
+            o.strict,
+        ),
+        SubCommand::Strandness(o) => strandness::run(
+            o.annotation,
+            o.bam,
+            o.fa,
+            o.filter.read_filter(),
+            o.sample_size,
+            o.auto_index,
+        ),
+    }
+}
+
+fn chrom_alias(path: Option<&Path>) -> error::Result<alias::ChromAlias> {
+    match path {
+        Some(path) => alias::ChromAlias::from_path(path),
+        None => Ok(alias::ChromAlias::default()),
     }
 }
 
@@ -161,3 +559,16 @@ fn file_path_validation(path: &str) -> Result<(), String> {
         Ok(())
     }
 }
+
+// match the whole contig name
+fn contig_regex(s: &str) -> Result<regex::Regex, regex::Error> {
+    regex::Regex::new(&format!("^(?:{s})$"))
+}
+
+fn tag_validation(tag: &str) -> Result<(), String> {
+    if tag.len() == 2 && tag.is_ascii() {
+        Ok(())
+    } else {
+        Err(format!("{tag:?} is not a two-character tag"))
+    }
+}
@@ -0,0 +1,235 @@
+use crate::error::{Error, Result};
+use clap::ArgEnum;
+use rust_htslib::{htslib, utils};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Format of the output of `base`.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// tab-separated counts
+    Tsv,
+    /// one record per site with allele depths
+    Vcf,
+    /// binary vcf
+    Bcf,
+    /// columnar counts in parquet
+    Parquet,
+    /// columnar counts in arrow ipc (feather v2)
+    Arrow,
+    /// read bases and qualities like `samtools mpileup`
+    Samtools,
+}
+
+impl OutputFormat {
+    /// Format told by the extension of the output file (`.vcf`, `.vcf.gz`, `.bcf`, `.parquet`,
+    /// `.arrow`), tsv otherwise.
+    pub fn from_path(path: Option<&Path>) -> Self {
+        let name = path
+            .map(|p| p.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if name.ends_with(".parquet") || name.ends_with(".pq") {
+            return OutputFormat::Parquet;
+        }
+        if name.ends_with(".arrow") || name.ends_with(".ipc") || name.ends_with(".feather") {
+            return OutputFormat::Arrow;
+        }
+        let name = name
+            .strip_suffix(".gz")
+            .or_else(|| name.strip_suffix(".bgz"))
+            .unwrap_or(&name);
+        if name.ends_with(".vcf") {
+            OutputFormat::Vcf
+        } else if name.ends_with(".bcf") {
+            OutputFormat::Bcf
+        } else {
+            OutputFormat::Tsv
+        }
+    }
+
+    /// Whether the counts are written in typed columns (parquet or arrow).
+    pub fn is_columnar(self) -> bool {
+        matches!(self, OutputFormat::Parquet | OutputFormat::Arrow)
+    }
+}
+
+/// Counts of a site on a strand, written as a line of long format or a row of parquet/arrow.
+pub struct Row {
+    /// 0-based position
+    pub pos: u32,
+    pub strand: &'static str,
+    pub ref_base: char,
+    /// output column (sample, or tag group of a sample) of long format, all columns otherwise
+    pub label: Option<usize>,
+    pub counts: Vec<u32>,
+}
+
+/// Whether the output file is to be indexed (bgzip compressed or bcf), which needs records sorted
+/// by position.
+pub fn is_indexed(path: Option<&Path>, format: OutputFormat) -> bool {
+    path.is_some_and(|p| format == OutputFormat::Bcf || (!format.is_columnar() && is_bgzf(p)))
+}
+
+/// Destination of the tabular output: stdout, a plain text file, or a bgzip compressed file
+/// (`.gz` or `.bgz`) that is tabix indexed on chrom and pos once finished.
+pub struct Output {
+    writer: BufWriter<Sink>,
+    path: Option<PathBuf>,
+}
+
+enum Sink {
+    Stdout(io::Stdout),
+    File(File),
+    Bgzf(Bgzf),
+}
+
+impl Output {
+    pub fn create(path: Option<&Path>, hts_threads: usize) -> Result<Self> {
+        let err = |e| Error::Write(io::Error::other(e));
+        let sink = match path {
+            None => Sink::Stdout(io::stdout()),
+            Some(p) if is_bgzf(p) => Sink::Bgzf(Bgzf::create(p, hts_threads).map_err(err)?),
+            Some(p) => Sink::File(
+                File::create(p).map_err(|e| err(format!("Failed to create {:?}: {}", p, e)))?,
+            ),
+        };
+        Ok(Output {
+            writer: BufWriter::new(sink),
+            path: path.map(|p| p.to_path_buf()),
+        })
+    }
+
+    /// Whether the output is to be indexed, which needs records sorted by position.
+    pub fn is_indexed(&self) -> bool {
+        matches!(self.writer.get_ref(), Sink::Bgzf(_))
+    }
+
+    /// Flush and close the output, and build the tabix index (`.tbi`) of a bgzip compressed
+    /// file, skipping the first `header_lines` lines. Positions are 1-based unless `zero_based`.
+    pub fn finish(self, header_lines: usize, zero_based: bool) -> Result<()> {
+        let sink = self.writer.into_inner().map_err(|e| e.into_error())?;
+        match sink {
+            Sink::Stdout(mut s) => s.flush()?,
+            Sink::File(mut f) => f.flush()?,
+            Sink::Bgzf(b) => {
+                b.close()?;
+                // `is_indexed` makes sure of the path
+                build_tabix(self.path.as_deref().unwrap(), header_lines, zero_based)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Stdout(s) => s.write(buf),
+            Sink::File(f) => f.write(buf),
+            Sink::Bgzf(b) => b.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Stdout(s) => s.flush(),
+            Sink::File(f) => f.flush(),
+            Sink::Bgzf(b) => b.flush(),
+        }
+    }
+}
+
+// bgzip compressed file written by htslib
+struct Bgzf {
+    fp: *mut htslib::BGZF,
+}
+
+impl Bgzf {
+    fn create(path: &Path, hts_threads: usize) -> std::result::Result<Self, String> {
+        let c_path = utils::path_to_cstring(&path).ok_or(format!("Invalid path {:?}.", path))?;
+        let fp = unsafe { htslib::bgzf_open(c_path.as_ptr(), c"w".as_ptr()) };
+        if fp.is_null() {
+            return Err(format!("Failed to create {:?}.", path));
+        }
+        if hts_threads > 0 {
+            unsafe { htslib::bgzf_mt(fp, hts_threads as i32, 256) };
+        }
+        Ok(Bgzf { fp })
+    }
+
+    fn close(mut self) -> io::Result<()> {
+        let ret = unsafe { htslib::bgzf_close(self.fp) };
+        self.fp = std::ptr::null_mut();
+        if ret != 0 {
+            return Err(io::Error::other("failed to close bgzip file"));
+        }
+        Ok(())
+    }
+}
+
+impl Write for Bgzf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = unsafe { htslib::bgzf_write(self.fp, buf.as_ptr() as *const _, buf.len() as _) };
+        if n < 0 {
+            return Err(io::Error::other("failed to write bgzip file"));
+        }
+        Ok(n as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if unsafe { htslib::bgzf_flush(self.fp) } != 0 {
+            return Err(io::Error::other("failed to flush bgzip file"));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Bgzf {
+    fn drop(&mut self) {
+        if !self.fp.is_null() {
+            unsafe { htslib::bgzf_close(self.fp) };
+        }
+    }
+}
+
+fn is_bgzf(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("gz") || e.eq_ignore_ascii_case("bgz"))
+}
+
+// tabix index on the first two columns (chrom and pos), `#` lines skipped as well
+fn build_tabix(path: &Path, header_lines: usize, zero_based: bool) -> Result<()> {
+    let preset = if zero_based {
+        htslib::TBX_GENERIC | htslib::TBX_UCSC
+    } else {
+        htslib::TBX_GENERIC
+    };
+    let conf = htslib::tbx_conf_t {
+        preset: preset as i32,
+        sc: 1,
+        bc: 2,
+        ec: 0,
+        meta_char: b'#' as i32,
+        line_skip: header_lines as i32,
+    };
+    let c_path = utils::path_to_cstring(&path)
+        .ok_or_else(|| Error::Index(format!("Invalid path {:?}.", path)))?;
+    if unsafe { htslib::tbx_index_build(c_path.as_ptr(), 0, &conf) } != 0 {
+        return Err(Error::Index(format!(
+            "Failed to build tabix index of {:?}.",
+            path
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,426 @@
+use crate::alias::ChromAlias;
+use crate::error::{Error, Result};
+use itertools::Itertools;
+use rust_htslib::bgzf;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// Bed-like region, 0-based and half-open: [start, end).
+#[derive(Debug)]
+pub struct PosRecord {
+    pub chrom: String,
+    pub start: u32,
+    pub end: u32,
+    /// name column of bed
+    pub name: Option<String>,
+    /// strand column of bed, `+` or `-`
+    pub strand: Option<char>,
+}
+
+impl PosRecord {
+    pub fn new(chrom: String, start: u32, end: u32) -> Self {
+        PosRecord {
+            chrom,
+            start,
+            end,
+            name: None,
+            strand: None,
+        }
+    }
+}
+
+/// Read bed file (BED3 to BED12, plain or gzipped), skipping comment, `track` and `browser` lines.
+pub fn read_bed(region_path: &Path) -> Result<Vec<PosRecord>> {
+    read_records(region_path, |line| parse_bed_line(line).map(Some))
+}
+
+/// Read exons from gtf/gff file (plain or gzipped).
+pub fn read_gtf(region_path: &Path) -> Result<Vec<PosRecord>> {
+    read_records(region_path, parse_gtf_line)
+}
+
+fn read_records(
+    region_path: &Path,
+    parse_line: impl Fn(&str) -> std::result::Result<Option<PosRecord>, String>,
+) -> Result<Vec<PosRecord>> {
+    let reader = bgzf::Reader::from_path(region_path)
+        .map_err(|e| Error::Input(format!("Failed to open {:?}: {}", region_path, e)))?;
+    let mut records = Vec::new();
+    for (i, line) in BufReader::new(reader).lines().enumerate() {
+        let line =
+            line.map_err(|e| Error::Input(format!("Failed to read {:?}: {}", region_path, e)))?;
+        let line = line.trim_end();
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+        {
+            continue;
+        }
+        let record = parse_line(line)
+            .map_err(|e| Error::Input(format!("{:?} line {}: {}", region_path, i + 1, e)))?;
+        // skip empty regions
+        if let Some(record) = record.filter(|r| r.start < r.end) {
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+// exon lines only, converted from 1-based inclusive
+fn parse_gtf_line(line: &str) -> std::result::Result<Option<PosRecord>, String> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() < 9 {
+        return Err(format!("expect 9 columns, found {}.", fields.len()));
+    }
+    if fields[2] != "exon" {
+        return Ok(None);
+    }
+    let start = fields[3]
+        .parse::<u32>()
+        .map_err(|_| format!("invalid start `{}`.", fields[3]))?;
+    let end = fields[4]
+        .parse::<u32>()
+        .map_err(|_| format!("invalid end `{}`.", fields[4]))?;
+    if start == 0 || start > end {
+        return Err(format!("invalid range {start}-{end}."));
+    }
+    let strand = match fields[6] {
+        "+" => Some('+'),
+        "-" => Some('-'),
+        _ => None,
+    };
+    Ok(Some(PosRecord {
+        chrom: fields[0].to_string(),
+        start: start - 1,
+        end,
+        name: None,
+        strand,
+    }))
+}
+
+fn parse_bed_line(line: &str) -> std::result::Result<PosRecord, String> {
+    let fields: Vec<&str> = if line.contains('\t') {
+        line.split('\t').collect()
+    } else {
+        line.split_whitespace().collect()
+    };
+    if fields.len() < 3 {
+        return Err(format!(
+            "expect at least 3 columns, found {}.",
+            fields.len()
+        ));
+    }
+    let start = fields[1]
+        .parse::<u32>()
+        .map_err(|_| format!("invalid start `{}`.", fields[1]))?;
+    let end = fields[2]
+        .parse::<u32>()
+        .map_err(|_| format!("invalid end `{}`.", fields[2]))?;
+    if start > end {
+        return Err(format!("start ({start}) is greater than end ({end})."));
+    }
+    let name = fields.get(3).filter(|n| **n != ".").map(|n| n.to_string());
+    let strand = match fields.get(5) {
+        None | Some(&".") => None,
+        Some(&"+") => Some('+'),
+        Some(&"-") => Some('-'),
+        Some(s) => return Err(format!("invalid strand `{s}`.")),
+    };
+    Ok(PosRecord {
+        chrom: fields[0].to_string(),
+        start,
+        end,
+        name,
+        strand,
+    })
+}
+
+/// Contig lengths from the fasta index (`.fai` next to the fasta file).
+pub fn fai_lengths(fasta_path: &Path) -> Result<HashMap<String, u64>> {
+    let mut fai_path = fasta_path.as_os_str().to_owned();
+    fai_path.push(".fai");
+    let err = |e: String| Error::Index(format!("Failed to read {:?}: {}", fai_path, e));
+    let mut fai_reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(b'\t')
+        .from_path(&fai_path)
+        .map_err(|e| err(e.to_string()))?;
+    let mut fai_len: HashMap<String, u64> = HashMap::new();
+    for record in fai_reader.records() {
+        let record = record.map_err(|e| err(e.to_string()))?;
+        let len = record
+            .get(1)
+            .and_then(|l| l.parse().ok())
+            .ok_or_else(|| err(format!("invalid line `{}`.", record.as_slice())))?;
+        fai_len.insert(record[0].to_string(), len);
+    }
+    Ok(fai_len)
+}
+
+/// Check regions against contig lengths of the reference, renaming contigs to their names in the
+/// reference. Regions on contigs missing from the reference are dropped and regions beyond the
+/// contig end are clipped, with a warning, or an error if `strict` is set.
+pub fn clip_regions(
+    regions: Vec<PosRecord>,
+    contig_len: &HashMap<String, u64>,
+    alias: &ChromAlias,
+    strict: bool,
+) -> Result<Vec<PosRecord>> {
+    let mut missing: Vec<String> = Vec::new();
+    let mut n_clipped = 0;
+    let mut clipped = Vec::with_capacity(regions.len());
+    let mut renamed: HashMap<String, Option<String>> = HashMap::new();
+    for mut r in regions {
+        let chrom = renamed
+            .entry(r.chrom.clone())
+            .or_insert_with(|| alias.resolve(&r.chrom, |c| contig_len.contains_key(c)));
+        let len = match chrom {
+            Some(chrom) => {
+                r.chrom.clone_from(chrom);
+                contig_len[chrom] as u32
+            }
+            None => {
+                if strict {
+                    return Err(Error::Contig(format!(
+                        "Contig `{}` is not in the reference.",
+                        r.chrom
+                    )));
+                }
+                if !missing.contains(&r.chrom) {
+                    missing.push(r.chrom.clone());
+                }
+                continue;
+            }
+        };
+        if r.end > len {
+            if strict {
+                return Err(Error::Contig(format!(
+                    "Region {}:{}-{} is beyond the end of contig ({}).",
+                    r.chrom, r.start, r.end, len
+                )));
+            }
+            n_clipped += 1;
+            r.end = len;
+        }
+        if r.start < r.end {
+            clipped.push(r);
+        }
+    }
+    for chrom in missing {
+        eprintln!("Contig `{}` is not in the reference, skipped.", chrom);
+    }
+    if n_clipped > 0 {
+        eprintln!(
+            "{} regions beyond the end of contig are clipped.",
+            n_clipped
+        );
+    }
+    Ok(clipped)
+}
+
+/// Sort regions by position within each chromosome (chromosomes in order of appearance), and
+/// merge overlapping or adjacent regions (of the same strand if `by_strand`).
+pub fn merge_regions(regions: Vec<PosRecord>, by_strand: bool) -> Vec<PosRecord> {
+    let n_input = regions.len();
+    let input_bp: u64 = regions.iter().map(|r| (r.end - r.start) as u64).sum();
+
+    let mut chrom_order: HashMap<String, usize> = HashMap::new();
+    for r in &regions {
+        let n = chrom_order.len();
+        chrom_order.entry(r.chrom.clone()).or_insert(n);
+    }
+    let strand_key = |r: &PosRecord| if by_strand { r.strand } else { None };
+    let mut merged: Vec<PosRecord> = Vec::new();
+    for r in regions
+        .into_iter()
+        .sorted_by_key(|r| (chrom_order[&r.chrom], strand_key(r), r.start, r.end))
+    {
+        match merged.last_mut() {
+            Some(last)
+                if last.chrom == r.chrom
+                    && strand_key(last) == strand_key(&r)
+                    && r.start <= last.end =>
+            {
+                last.end = last.end.max(r.end);
+            }
+            _ => merged.push(r),
+        }
+    }
+    if by_strand {
+        merged.sort_by_key(|r| (chrom_order[&r.chrom], r.start, r.end));
+    }
+
+    let merged_bp: u64 = merged.iter().map(|r| (r.end - r.start) as u64).sum();
+    if merged.len() < n_input {
+        eprintln!(
+            "Merged {} target regions ({} bp) into {} non-overlapping regions ({} bp), {} bp of duplication removed.",
+            n_input,
+            input_bp,
+            merged.len(),
+            merged_bp,
+            input_bp - merged_bp
+        );
+    }
+    merged
+}
+
+/// Parse a samtools-style region string: `chr`, `chr:start` (to the end of contig) or
+/// `chr:start-end`, where positions are 1-based and inclusive.
+pub fn parse_region_str(
+    region: &str,
+    contig_len: &HashMap<String, u64>,
+    alias: &ChromAlias,
+) -> Result<PosRecord> {
+    let exists = |c: &str| contig_len.contains_key(c);
+    // contig names may contain ':', so try the whole string first
+    if let Some(chrom) = alias.resolve(region, exists) {
+        let len = contig_len[&chrom];
+        return Ok(PosRecord::new(chrom, 0, len as u32));
+    }
+    let (chrom, range) = region
+        .rsplit_once(':')
+        .ok_or_else(|| Error::Contig(format!("Contig `{region}` is not in the reference.")))?;
+    let chrom = alias
+        .resolve(chrom, exists)
+        .ok_or_else(|| Error::Contig(format!("Contig `{chrom}` is not in the reference.")))?;
+    let len = contig_len[&chrom];
+
+    let parse_pos = |s: &str| {
+        s.replace(',', "")
+            .parse::<u64>()
+            .map_err(|_| Error::Param(format!("Invalid position `{s}` in region `{region}`.")))
+    };
+    let (start, end) = match range.split_once('-') {
+        Some((start, "")) => (parse_pos(start)?, len),
+        Some((start, end)) => (parse_pos(start)?, parse_pos(end)?.min(len)),
+        None => (parse_pos(range)?, len),
+    };
+    if start == 0 || start > end {
+        return Err(Error::Param(format!("Invalid range in region `{region}`.")));
+    }
+    Ok(PosRecord::new(chrom, (start - 1) as u32, end as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contigs() -> HashMap<String, u64> {
+        HashMap::from([("chr1".to_string(), 1000), ("XII".to_string(), 2000)])
+    }
+
+    fn parse(s: &str) -> Result<PosRecord> {
+        parse_region_str(s, &contigs(), &ChromAlias::default())
+    }
+
+    #[test]
+    fn region_str_is_converted_to_half_open() {
+        let r = parse("XII:101-200").unwrap();
+        assert_eq!((r.chrom.as_str(), r.start, r.end), ("XII", 100, 200));
+        let r = parse("XII:5-5").unwrap();
+        assert_eq!((r.start, r.end), (4, 5));
+    }
+
+    #[test]
+    fn region_str_without_end_runs_to_contig_end() {
+        let r = parse("XII").unwrap();
+        assert_eq!((r.start, r.end), (0, 2000));
+        let r = parse("XII:1001").unwrap();
+        assert_eq!((r.start, r.end), (1000, 2000));
+        let r = parse("XII:1001-").unwrap();
+        assert_eq!((r.start, r.end), (1000, 2000));
+        // end beyond the contig is clipped
+        let r = parse("XII:1001-5000").unwrap();
+        assert_eq!((r.start, r.end), (1000, 2000));
+    }
+
+    #[test]
+    fn region_str_accepts_commas_and_chr_prefix() {
+        let r = parse("XII:1,001-1,100").unwrap();
+        assert_eq!((r.start, r.end), (1000, 1100));
+        let r = parse("1:11-20").unwrap();
+        assert_eq!((r.chrom.as_str(), r.start, r.end), ("chr1", 10, 20));
+    }
+
+    #[test]
+    fn invalid_region_str() {
+        assert!(matches!(parse("XII:0-10"), Err(Error::Param(_))));
+        assert!(matches!(parse("XII:20-10"), Err(Error::Param(_))));
+        assert!(matches!(parse("XII:a-10"), Err(Error::Param(_))));
+        assert!(matches!(parse("chr9:1-10"), Err(Error::Contig(_))));
+    }
+
+    #[test]
+    fn bed_line_of_any_width() {
+        let r = parse_bed_line("XII\t100\t200").unwrap();
+        assert_eq!((r.chrom.as_str(), r.start, r.end), ("XII", 100, 200));
+        assert_eq!((r.name, r.strand), (None, None));
+        let r = parse_bed_line("XII\t100\t200\tgene1\t0\t-\t100\t200\t0\t1\t100,\t0,").unwrap();
+        assert_eq!((r.name.as_deref(), r.strand), (Some("gene1"), Some('-')));
+        // whitespace separated, `.` for missing name and strand
+        let r = parse_bed_line("XII 100 200 . 0 .").unwrap();
+        assert_eq!((r.start, r.end, r.name, r.strand), (100, 200, None, None));
+    }
+
+    #[test]
+    fn invalid_bed_line() {
+        assert!(parse_bed_line("XII\t100").is_err());
+        assert!(parse_bed_line("XII\t200\t100").is_err());
+        assert!(parse_bed_line("XII\tx\t100").is_err());
+        assert!(parse_bed_line("XII\t100\t200\tn\t0\t*").is_err());
+    }
+
+    fn stranded(chrom: &str, start: u32, end: u32, strand: char) -> PosRecord {
+        PosRecord {
+            strand: Some(strand),
+            ..PosRecord::new(chrom.to_string(), start, end)
+        }
+    }
+
+    fn spans(regions: &[PosRecord]) -> Vec<(&str, u32, u32)> {
+        regions
+            .iter()
+            .map(|r| (r.chrom.as_str(), r.start, r.end))
+            .collect()
+    }
+
+    #[test]
+    fn merge_overlapping_and_adjacent_regions() {
+        let regions = vec![
+            PosRecord::new("XII".to_string(), 300, 400),
+            PosRecord::new("chr1".to_string(), 0, 10),
+            PosRecord::new("XII".to_string(), 100, 200),
+            PosRecord::new("XII".to_string(), 150, 250),
+            PosRecord::new("XII".to_string(), 400, 450),
+            PosRecord::new("XII".to_string(), 100, 200),
+        ];
+        // contigs kept in order of appearance, positions sorted within each
+        assert_eq!(
+            spans(&merge_regions(regions, false)),
+            vec![("XII", 100, 250), ("XII", 300, 450), ("chr1", 0, 10)]
+        );
+    }
+
+    #[test]
+    fn merge_regions_by_strand() {
+        let regions = vec![
+            stranded("XII", 100, 200, '+'),
+            stranded("XII", 150, 250, '-'),
+            stranded("XII", 180, 300, '+'),
+        ];
+        let merged = merge_regions(regions, true);
+        assert_eq!(spans(&merged), vec![("XII", 100, 300), ("XII", 150, 250)]);
+        assert_eq!(merged[0].strand, Some('+'));
+        let regions = vec![
+            stranded("XII", 100, 200, '+'),
+            stranded("XII", 150, 250, '-'),
+        ];
+        assert_eq!(
+            spans(&merge_regions(regions, false)),
+            vec![("XII", 100, 250)]
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use crate::error::{Error, Result};
+use crate::hts;
+use crate::strand::LibraryType;
+use clap::ArgEnum;
+use rust_htslib::bam::{self, Read};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// An input bam/cram file, reported as one column (or one column per tag group) in the output.
+pub struct Sample {
+    pub name: String,
+    pub path: PathBuf,
+    /// group or condition of the sample
+    pub group: Option<String>,
+    pub library_type: LibraryType,
+}
+
+/// Samples from the input files, named by their paths.
+pub fn from_paths(bam_path_list: Vec<PathBuf>, library_type: LibraryType) -> Vec<Sample> {
+    bam_path_list
+        .into_iter()
+        .map(|path| Sample {
+            name: path.to_string_lossy().to_string(),
+            path,
+            group: None,
+            library_type,
+        })
+        .collect()
+}
+
+/// Read a tab-separated sample sheet: name, path, optional group and optional library type,
+/// skipping `#` comments. Empty (or `.`) columns fall back to the `SM` tag of `@RG` for name,
+/// and to `library_type` for library type.
+pub fn read_sample_sheet(sheet_path: &Path, library_type: LibraryType) -> Result<Vec<Sample>> {
+    let content = fs::read_to_string(sheet_path)
+        .map_err(|e| Error::Input(format!("Failed to read {:?}: {}", sheet_path, e)))?;
+    let mut samples = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let err = |e: String| Error::Input(format!("{:?} line {}: {}", sheet_path, i + 1, e));
+        let fields: Vec<&str> = line.split('\t').map(|f| f.trim()).collect();
+        let field = |j: usize| {
+            fields
+                .get(j)
+                .copied()
+                .filter(|f| !f.is_empty() && *f != ".")
+        };
+        let path = PathBuf::from(field(1).ok_or_else(|| err("missing bam path.".to_string()))?);
+        if !path.is_file() {
+            return Err(err(format!("{path:?} file doesn't exists")));
+        }
+        let name = match field(0) {
+            Some(name) => name.to_string(),
+            None => rg_sample(&path)?.unwrap_or_else(|| path.to_string_lossy().to_string()),
+        };
+        let library_type = match field(3) {
+            Some(s) => LibraryType::from_str(s, true)
+                .map_err(|_| err(format!("invalid library type `{s}`.")))?,
+            None => library_type,
+        };
+        samples.push(Sample {
+            name,
+            path,
+            group: field(2).map(|g| g.to_string()),
+            library_type,
+        });
+    }
+    if samples.is_empty() {
+        return Err(Error::Input(format!(
+            "No sample found in {:?}.",
+            sheet_path
+        )));
+    }
+    let mut names = HashSet::new();
+    for s in &samples {
+        if !names.insert(&s.name) {
+            return Err(Error::Input(format!(
+                "Sample name `{}` is duplicated in {:?}.",
+                s.name, sheet_path
+            )));
+        }
+    }
+    Ok(samples)
+}
+
+// sample name from the `SM` tag of read groups, if all read groups agree
+fn rg_sample(bam_path: &Path) -> Result<Option<String>> {
+    let bam_reader = hts::open_bam(bam_path, None, None)?;
+    let header = bam::Header::from_template(bam_reader.header()).to_hashmap();
+    let names: HashSet<&String> = header
+        .get("RG")
+        .into_iter()
+        .flatten()
+        .filter_map(|rg| rg.get("SM"))
+        .collect();
+    Ok(if names.len() == 1 {
+        names.into_iter().next().cloned()
+    } else {
+        None
+    })
+}
@@ -0,0 +1,203 @@
+use rust_htslib::bam::record::{Cigar, Record};
+
+/// A read at a site, as written by `samtools mpileup`.
+pub struct PileupRead {
+    /// read base, or `*` for a deletion and `>` for a reference skip
+    pub base: u8,
+    pub qual: u8,
+    pub mapq: u8,
+    /// 0-based position in the read (of the next base for a deletion)
+    pub qpos: usize,
+    pub reverse: bool,
+    /// first and last site of the read
+    pub head: bool,
+    pub tail: bool,
+    /// sequence inserted after the site
+    pub ins: Option<Vec<u8>>,
+    /// length of the deletion after the site
+    pub del: Option<u32>,
+}
+
+/// Position in the read of the base after a deletion or reference skip at `ref_pos`, whose
+/// quality is reported by samtools.
+pub fn qpos_after_gap(record: &Record, ref_pos: u32) -> usize {
+    let mut rpos = record.pos() as u32;
+    let mut qpos = 0;
+    for c in record.cigar().iter() {
+        match c {
+            Cigar::Match(l) | Cigar::Equal(l) | Cigar::Diff(l) => {
+                rpos += l;
+                qpos += *l as usize;
+            }
+            Cigar::Ins(l) | Cigar::SoftClip(l) => qpos += *l as usize,
+            Cigar::Del(l) | Cigar::RefSkip(l) => {
+                if rpos + l > ref_pos {
+                    break;
+                }
+                rpos += l;
+            }
+            Cigar::HardClip(_) | Cigar::Pad(_) => {}
+        }
+    }
+    qpos.min(record.seq_len().saturating_sub(1))
+}
+
+/// Columns of a sample: depth, read bases, base qualities, and optionally mapping qualities and
+/// 1-based positions in the read. `ref_seq` is the reference from the site on, long enough to
+/// cover the deletions.
+pub fn format_sample(
+    reads: &[PileupRead],
+    ref_seq: &[u8],
+    output_mapq: bool,
+    output_pos: bool,
+) -> String {
+    if reads.is_empty() {
+        let mut fields = vec!["0", "*", "*"];
+        if output_mapq {
+            fields.push("*");
+        }
+        if output_pos {
+            fields.push("*");
+        }
+        return fields.join("\t");
+    }
+    let ref_base = ref_seq[0].to_ascii_uppercase();
+    let strand_case = |b: u8, reverse: bool| {
+        if reverse {
+            b.to_ascii_lowercase()
+        } else {
+            b.to_ascii_uppercase()
+        }
+    };
+    let mut bases: Vec<u8> = Vec::new();
+    for r in reads {
+        if r.head {
+            bases.push(b'^');
+            bases.push(r.mapq.min(93) + 33);
+        }
+        bases.push(match r.base {
+            b'*' => b'*',
+            b'>' if r.reverse => b'<',
+            b'>' => b'>',
+            b if b.to_ascii_uppercase() == ref_base && r.reverse => b',',
+            b if b.to_ascii_uppercase() == ref_base => b'.',
+            b => strand_case(b, r.reverse),
+        });
+        if let Some(seq) = &r.ins {
+            bases.extend(format!("+{}", seq.len()).bytes());
+            bases.extend(seq.iter().map(|b| strand_case(*b, r.reverse)));
+        }
+        if let Some(len) = r.del {
+            let deleted = ref_seq.get(1..1 + len as usize).unwrap_or(&ref_seq[1..]);
+            bases.extend(format!("-{}", len).bytes());
+            bases.extend(deleted.iter().map(|b| strand_case(*b, r.reverse)));
+        }
+        if r.tail {
+            bases.push(b'$');
+        }
+    }
+    let mut fields = vec![
+        reads.len().to_string(),
+        String::from_utf8_lossy(&bases).to_string(),
+        reads
+            .iter()
+            .map(|r| (r.qual.min(93) + 33) as char)
+            .collect(),
+    ];
+    if output_mapq {
+        fields.push(
+            reads
+                .iter()
+                .map(|r| (r.mapq.min(93) + 33) as char)
+                .collect(),
+        );
+    }
+    if output_pos {
+        fields.push(
+            reads
+                .iter()
+                .map(|r| (r.qpos + 1).to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+    }
+    fields.join("\t")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::CigarString;
+
+    fn read(base: u8, reverse: bool) -> PileupRead {
+        PileupRead {
+            base,
+            qual: 30,
+            mapq: 60,
+            qpos: 9,
+            reverse,
+            head: false,
+            tail: false,
+            ins: None,
+            del: None,
+        }
+    }
+
+    #[test]
+    fn read_bases_with_markers() {
+        let reads = [
+            PileupRead {
+                head: true,
+                ..read(b'A', false)
+            },
+            read(b'G', true),
+            PileupRead {
+                ins: Some(b"TT".to_vec()),
+                ..read(b'a', false)
+            },
+            PileupRead {
+                del: Some(2),
+                ..read(b'A', true)
+            },
+            PileupRead {
+                tail: true,
+                qual: 10,
+                mapq: 0,
+                qpos: 0,
+                ..read(b'C', false)
+            },
+            read(b'*', false),
+            read(b'>', true),
+        ];
+        assert_eq!(
+            format_sample(&reads, b"aCG", true, true),
+            "7\t^].g.+2TT,-2cgC$*<\t????+??\t]]]]!]]\t10,10,10,10,1,10,10"
+        );
+        assert_eq!(
+            format_sample(&reads[..2], b"ACG", false, false),
+            "2\t^].g\t??"
+        );
+    }
+
+    #[test]
+    fn sample_without_reads() {
+        assert_eq!(format_sample(&[], b"A", false, false), "0\t*\t*");
+        assert_eq!(format_sample(&[], b"A", true, true), "0\t*\t*\t*\t*");
+    }
+
+    #[test]
+    fn quality_after_deletion() {
+        let mut record = Record::new();
+        let cigar = CigarString(vec![
+            Cigar::SoftClip(5),
+            Cigar::Match(10),
+            Cigar::Del(3),
+            Cigar::Match(10),
+        ]);
+        record.set(b"r1", Some(&cigar), &[b'A'; 25], &[30; 25]);
+        record.set_pos(100);
+        // deleted reference 110-112, the next base is the 16th of the read
+        assert_eq!(qpos_after_gap(&record, 111), 15);
+        assert_eq!(qpos_after_gap(&record, 110), 15);
+    }
+}
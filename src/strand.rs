@@ -0,0 +1,99 @@
+use clap::ArgEnum;
+use rust_htslib::bam::{self, record::Aux};
+
+/// How the strand of the transcript is told from a read.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LibraryType {
+    /// read1 maps to the reverse of transcript (dUTP, NSR, NNSR)
+    FrFirststrand,
+    /// read1 maps to the transcript (Ligation, Standard SOLiD)
+    FrSecondstrand,
+    /// no strand info, use the strand of each alignment
+    Unstranded,
+    /// single-end, read maps to the transcript
+    SingleForward,
+    /// single-end, read maps to the reverse of transcript
+    SingleReverse,
+    /// use the `XS` tag (or `TS` tag, relative to read), reads without tag are skipped
+    XsTag,
+}
+
+impl LibraryType {
+    /// Strand of the transcript the read comes from, `None` if it can't be told.
+    pub fn strand(&self, record: &bam::Record) -> Option<char> {
+        let reverse = record.flags() & 16 == 16;
+        let read2 = record.flags() & 128 == 128;
+        let fwd = match self {
+            LibraryType::FrSecondstrand => reverse == read2,
+            LibraryType::FrFirststrand => reverse != read2,
+            LibraryType::Unstranded | LibraryType::SingleForward => !reverse,
+            LibraryType::SingleReverse => reverse,
+            LibraryType::XsTag => {
+                if let Ok(Aux::Char(c)) = record.aux(b"XS") {
+                    c == b'+'
+                } else if let Ok(Aux::Char(c)) = record.aux(b"TS") {
+                    (c == b'+') != reverse
+                } else {
+                    return None;
+                }
+            }
+        };
+        Some(if fwd { '+' } else { '-' })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bam::record::Record;
+
+    // read1 forward, read1 reverse, read2 forward and read2 reverse
+    const READS: [u16; 4] = [0x41, 0x51, 0x81, 0x91];
+
+    fn record(flags: u16, tag: Option<(&[u8], u8)>) -> Record {
+        let mut record = Record::new();
+        record.set(b"r1", None, b"ACGT", &[30; 4]);
+        record.set_flags(flags);
+        if let Some((tag, c)) = tag {
+            record.push_aux(tag, Aux::Char(c)).unwrap();
+        }
+        record
+    }
+
+    fn strands(library_type: LibraryType) -> Vec<Option<char>> {
+        READS
+            .iter()
+            .map(|&flags| library_type.strand(&record(flags, None)))
+            .collect()
+    }
+
+    #[test]
+    fn strand_by_read_and_orientation() {
+        let (fwd, rev) = (Some('+'), Some('-'));
+        assert_eq!(strands(LibraryType::FrSecondstrand), [fwd, rev, rev, fwd]);
+        assert_eq!(strands(LibraryType::FrFirststrand), [rev, fwd, fwd, rev]);
+        assert_eq!(strands(LibraryType::Unstranded), [fwd, rev, fwd, rev]);
+        assert_eq!(strands(LibraryType::SingleForward), [fwd, rev, fwd, rev]);
+        assert_eq!(strands(LibraryType::SingleReverse), [rev, fwd, rev, fwd]);
+    }
+
+    #[test]
+    fn strand_by_tag() {
+        let xs = LibraryType::XsTag;
+        assert_eq!(strands(xs), [None; 4]);
+        for flags in READS {
+            // XS is the strand of the transcript, TS is relative to the read
+            assert_eq!(xs.strand(&record(flags, Some((b"XS", b'+')))), Some('+'));
+            assert_eq!(xs.strand(&record(flags, Some((b"XS", b'-')))), Some('-'));
+            let reverse = flags & 0x10 != 0;
+            assert_eq!(
+                xs.strand(&record(flags, Some((b"TS", b'+')))),
+                Some(if reverse { '-' } else { '+' })
+            );
+            assert_eq!(
+                xs.strand(&record(flags, Some((b"TS", b'-')))),
+                Some(if reverse { '+' } else { '-' })
+            );
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use crate::alias::ChromAlias;
+use crate::error::{Error, Result};
+use crate::filter::ReadFilter;
+use crate::hts;
+use crate::region;
+use crate::strand::LibraryType;
+use clap::ArgEnum;
+use rust_htslib::bam::{self, Read};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// reads of one orientation above this fraction make a stranded library
+const STRANDED_FRACTION: f64 = 0.75;
+
+// annotated intervals of a contig, sorted and merged per strand
+#[derive(Default)]
+struct StrandIntervals {
+    fwd: Vec<(u32, u32)>,
+    rev: Vec<(u32, u32)>,
+}
+
+#[derive(Default)]
+struct StrandCount {
+    // paired-end reads, read1 on the strand of transcript or not
+    paired_same: usize,
+    paired_opposite: usize,
+    // single-end reads, read on the strand of transcript or not
+    single_same: usize,
+    single_opposite: usize,
+    // reads within annotation of both strands
+    undetermined: usize,
+}
+
+impl StrandCount {
+    fn total(&self) -> usize {
+        self.paired_same
+            + self.paired_opposite
+            + self.single_same
+            + self.single_opposite
+            + self.undetermined
+    }
+
+    fn library_type(&self) -> LibraryType {
+        let paired = self.paired_same + self.paired_opposite;
+        let single = self.single_same + self.single_opposite;
+        let (same, n, same_type, opposite_type) = if paired >= single {
+            (
+                self.paired_same,
+                paired,
+                LibraryType::FrSecondstrand,
+                LibraryType::FrFirststrand,
+            )
+        } else {
+            (
+                self.single_same,
+                single,
+                LibraryType::SingleForward,
+                LibraryType::SingleReverse,
+            )
+        };
+        if n == 0 {
+            return LibraryType::Unstranded;
+        }
+        let frac = same as f64 / n as f64;
+        if frac > STRANDED_FRACTION {
+            same_type
+        } else if frac < 1.0 - STRANDED_FRACTION {
+            opposite_type
+        } else {
+            LibraryType::Unstranded
+        }
+    }
+}
+
+fn merge_intervals(mut intervals: Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+    intervals.sort_unstable();
+    let mut merged: Vec<(u32, u32)> = Vec::new();
+    for (start, end) in intervals {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+// whether any of the sorted, non-overlapping intervals overlaps [start, end)
+fn overlaps(intervals: &[(u32, u32)], start: u32, end: u32) -> bool {
+    let i = intervals.partition_point(|iv| iv.0 < end);
+    i > 0 && intervals[i - 1].1 > start
+}
+
+fn read_annotation(annotation_path: &Path) -> Result<Vec<(String, StrandIntervals)>> {
+    let name = annotation_path.to_string_lossy().to_lowercase();
+    let name = name.strip_suffix(".gz").unwrap_or(&name);
+    let records = if name.ends_with(".gtf") || name.ends_with(".gff") || name.ends_with(".gff3") {
+        region::read_gtf(annotation_path)
+    } else {
+        region::read_bed(annotation_path)
+    }?;
+
+    let mut chrom_order: Vec<String> = Vec::new();
+    let mut chrom_intervals: HashMap<String, StrandIntervals> = HashMap::new();
+    for r in records {
+        let intervals = chrom_intervals.entry(r.chrom.clone()).or_insert_with(|| {
+            chrom_order.push(r.chrom.clone());
+            StrandIntervals::default()
+        });
+        match r.strand {
+            Some('+') => intervals.fwd.push((r.start, r.end)),
+            Some('-') => intervals.rev.push((r.start, r.end)),
+            _ => {}
+        }
+    }
+    Ok(chrom_order
+        .into_iter()
+        .map(|chrom| {
+            let intervals = chrom_intervals.remove(&chrom).unwrap();
+            (
+                chrom,
+                StrandIntervals {
+                    fwd: merge_intervals(intervals.fwd),
+                    rev: merge_intervals(intervals.rev),
+                },
+            )
+        })
+        .filter(|(_, intervals)| !intervals.fwd.is_empty() || !intervals.rev.is_empty())
+        .collect())
+}
+
+fn count_strand(
+    bam_path: &Path,
+    fasta_path: Option<&Path>,
+    annotation: &[(String, StrandIntervals)],
+    read_filter: &ReadFilter,
+    sample_size: usize,
+) -> Result<StrandCount> {
+    let mut count = StrandCount::default();
+    let mut bam_reader = hts::open_bam(bam_path, fasta_path, None)?;
+    let read_err = |e| Error::Input(format!("Failed to read {:?}: {}", bam_path, e));
+    let bam_header = bam_reader.header().clone();
+    let mut record = bam::Record::new();
+    for (chrom, intervals) in annotation {
+        let tid = match ChromAlias::default()
+            .resolve(chrom, |c| bam_header.tid(c.as_bytes()).is_some())
+            .and_then(|c| bam_header.tid(c.as_bytes()))
+        {
+            Some(tid) => tid,
+            None => continue,
+        };
+        let all = merge_intervals(
+            intervals
+                .fwd
+                .iter()
+                .chain(&intervals.rev)
+                .cloned()
+                .collect(),
+        );
+        for (start, end) in all {
+            bam_reader.fetch((tid, start, end)).map_err(read_err)?;
+            while let Some(r) = bam_reader.read(&mut record) {
+                r.map_err(read_err)?;
+                // count each read once, in the interval of its alignment start
+                if record.pos() < start as i64
+                    || !read_filter.pass_flags(&record)
+                    || !read_filter.pass_mapq(&record)
+                {
+                    continue;
+                }
+                let read_start = record.pos() as u32;
+                let read_end = record.cigar().end_pos() as u32;
+                let on_fwd = overlaps(&intervals.fwd, read_start, read_end);
+                let on_rev = overlaps(&intervals.rev, read_start, read_end);
+                if on_fwd == on_rev {
+                    if on_fwd {
+                        count.undetermined += 1;
+                    }
+                } else {
+                    let annotated = if on_fwd { '+' } else { '-' };
+                    if record.flags() & 1 == 1 {
+                        if LibraryType::FrSecondstrand.strand(&record) == Some(annotated) {
+                            count.paired_same += 1;
+                        } else {
+                            count.paired_opposite += 1;
+                        }
+                    } else if LibraryType::SingleForward.strand(&record) == Some(annotated) {
+                        count.single_same += 1;
+                    } else {
+                        count.single_opposite += 1;
+                    }
+                }
+                if count.total() >= sample_size {
+                    return Ok(count);
+                }
+            }
+        }
+    }
+    Ok(count)
+}
+
+pub fn run(
+    annotation_path: PathBuf,
+    bam_path_list: Vec<PathBuf>,
+    fasta_path: Option<PathBuf>,
+    read_filter: ReadFilter,
+    sample_size: usize,
+    auto_index: bool,
+) -> Result<()> {
+    hts::check_indexes(fasta_path.as_deref(), &bam_path_list, auto_index, 0)?;
+
+    let annotation = read_annotation(&annotation_path)?;
+    if annotation.is_empty() {
+        return Err(Error::Input(format!(
+            "No stranded region found in {:?}.",
+            annotation_path
+        )));
+    }
+
+    let mut handle = io::stdout().lock();
+    writeln!(
+        handle,
+        "Sample\tReads\tUndetermined\tfr-firststrand\tfr-secondstrand\tsingle-forward\tsingle-reverse\tLibraryType"
+    )?;
+    for bam_path in &bam_path_list {
+        let count = count_strand(
+            bam_path,
+            fasta_path.as_deref(),
+            &annotation,
+            &read_filter,
+            sample_size,
+        )?;
+        let n = count.total().max(1) as f64;
+        writeln!(
+            handle,
+            "{}\t{}\t{:.4}\t{:.4}\t{:.4}\t{:.4}\t{:.4}\t{}",
+            bam_path.display(),
+            count.total(),
+            count.undetermined as f64 / n,
+            count.paired_opposite as f64 / n,
+            count.paired_same as f64 / n,
+            count.single_same as f64 / n,
+            count.single_opposite as f64 / n,
+            count.library_type().to_possible_value().unwrap().get_name()
+        )?;
+    }
+    Ok(())
+}
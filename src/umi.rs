@@ -0,0 +1,120 @@
+use regex::bytes::Regex;
+use rust_htslib::bam::{self, record::Aux};
+use std::collections::HashMap;
+
+pub enum UmiSource {
+    /// UMI is the suffix of qname after the last separator, e.g. `READ:1:ACGTAC`
+    QnameSuffix(u8),
+    /// UMI is the first capture group (or the whole match) of a regex on qname
+    QnameRegex(Regex),
+    /// UMI is stored in a tag, e.g. `RX` or `UB`
+    Tag([u8; 2]),
+}
+
+pub struct UmiDedup {
+    pub source: UmiSource,
+    /// max edit distance between UMIs of the same molecule
+    pub max_dist: usize,
+}
+
+impl UmiDedup {
+    pub fn umi(&self, record: &bam::Record) -> Option<Vec<u8>> {
+        match &self.source {
+            UmiSource::QnameSuffix(sep) => {
+                let qname = record.qname();
+                let i = qname.iter().rposition(|c| c == sep)?;
+                Some(qname[i + 1..].to_vec())
+            }
+            UmiSource::QnameRegex(re) => {
+                let caps = re.captures(record.qname())?;
+                let m = caps.get(1).or_else(|| caps.get(0))?;
+                Some(m.as_bytes().to_vec())
+            }
+            UmiSource::Tag(tag) => match record.aux(tag) {
+                Ok(Aux::String(s)) => Some(s.as_bytes().to_vec()),
+                _ => None,
+            },
+        }
+    }
+
+    /// Keep the first read of each molecule, i.e. of each UMI, 5' position, strand and mate.
+    /// Reads without UMI are always kept.
+    pub fn dedup<T>(&self, reads: Vec<(T, bam::Record)>) -> Vec<(T, bam::Record)> {
+        let mut kept = Vec::with_capacity(reads.len());
+        let mut molecules: HashMap<(i64, u16), Vec<Vec<u8>>> = HashMap::new();
+        for (aln, record) in reads {
+            let umi = match self.umi(&record) {
+                Some(umi) => umi,
+                None => {
+                    kept.push((aln, record));
+                    continue;
+                }
+            };
+            let five_prime = if record.is_reverse() {
+                record.cigar().end_pos()
+            } else {
+                record.pos()
+            };
+            let seen = molecules
+                .entry((five_prime, record.flags() & (16 | 128)))
+                .or_default();
+            if seen
+                .iter()
+                .any(|u| edit_distance(u, &umi, self.max_dist) <= self.max_dist)
+            {
+                continue;
+            }
+            seen.push(umi);
+            kept.push((aln, record));
+        }
+        kept
+    }
+}
+
+// levenshtein distance, stop early once it exceeds `max_dist`
+fn edit_distance(a: &[u8], b: &[u8], max_dist: usize) -> usize {
+    if max_dist == 0 {
+        return usize::from(a != b);
+    }
+    if a.len().abs_diff(b.len()) > max_dist {
+        return max_dist + 1;
+    }
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, x) in a.iter().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, y) in b.iter().enumerate() {
+            let cost = if x == y { 0 } else { 1 };
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        if cur.iter().min().unwrap() > &max_dist {
+            return max_dist + 1;
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_match_without_distance() {
+        assert_eq!(edit_distance(b"ACGT", b"ACGT", 0), 0);
+        assert_eq!(edit_distance(b"ACGT", b"ACGA", 0), 1);
+    }
+
+    #[test]
+    fn substitution_insertion_deletion() {
+        assert_eq!(edit_distance(b"ACGT", b"ACCT", 2), 1);
+        assert_eq!(edit_distance(b"ACGT", b"ACGGT", 2), 1);
+        assert_eq!(edit_distance(b"ACGT", b"AGT", 2), 1);
+        assert_eq!(edit_distance(b"ACGT", b"TGCA", 4), 4);
+    }
+
+    #[test]
+    fn stop_beyond_max_distance() {
+        assert_eq!(edit_distance(b"AAAA", b"TTTT", 1), 2);
+        assert_eq!(edit_distance(b"ACGT", b"ACGTACGT", 2), 3);
+    }
+}
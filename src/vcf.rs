@@ -0,0 +1,409 @@
+use crate::error::{Error, Result};
+use crate::output::{self, OutputFormat};
+use rust_htslib::bcf;
+use rust_htslib::{htslib, utils};
+use std::collections::BTreeSet;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// order of base counters
+const BASES: &[u8; 4] = b"ACGT";
+
+/// Reads of a sample at a site, on the forward and reverse strand (as told by library type).
+pub struct SiteCounts<'a> {
+    /// counts of A, C, G, T
+    pub bases: [&'a [usize]; 2],
+    /// sequences inserted after the site
+    pub ins: [&'a [Vec<u8>]; 2],
+    /// lengths of deletions after the site
+    pub del: [&'a [u32]; 2],
+    /// counts of A, C, G, T at the site of the reads with an indel, included in `bases`
+    pub anchors: [&'a [usize]; 2],
+}
+
+enum Allele<'a> {
+    Ref,
+    Base(usize),
+    Ins(&'a [u8]),
+    Del(u32),
+}
+
+impl SiteCounts<'_> {
+    fn count(&self, allele: &Allele, ref_base: u8, strand: usize) -> usize {
+        match allele {
+            // reads with an indel are counted by their base of the site too, but belong to the
+            // indel
+            Allele::Ref => match BASES.iter().position(|b| *b == ref_base) {
+                Some(i) => self.bases[strand][i] - self.anchors[strand][i],
+                None => 0,
+            },
+            Allele::Base(i) => self.bases[strand][*i] - self.anchors[strand][*i],
+            Allele::Ins(seq) => self.ins[strand].iter().filter(|s| s == seq).count(),
+            Allele::Del(len) => self.del[strand].iter().filter(|l| *l == len).count(),
+        }
+    }
+
+    fn depth(&self) -> usize {
+        self.bases.iter().flat_map(|b| b.iter()).sum()
+    }
+}
+
+/// VCF header with contigs (name and length), the count fields and samples.
+pub fn header(
+    fasta_path: &Path,
+    contigs: &[(String, u64)],
+    sample_names: &[String],
+    count_indel: bool,
+) -> bcf::Header {
+    let mut header = bcf::Header::new();
+    header.push_record(b"##source=mpileup");
+    header.push_record(format!("##reference=file://{}", fasta_path.display()).as_bytes());
+    for (name, len) in contigs {
+        header.push_record(format!("##contig=<ID={},length={}>", name, len).as_bytes());
+    }
+    header.push_record(
+        br#"##INFO=<ID=DP,Number=1,Type=Integer,Description="Total read depth of all samples">"#,
+    );
+    header.push_record(
+        br#"##FORMAT=<ID=AD,Number=R,Type=Integer,Description="Read depth of each allele">"#,
+    );
+    header.push_record(br#"##FORMAT=<ID=DP,Number=1,Type=Integer,Description="Read depth">"#);
+    header.push_record(
+        br#"##FORMAT=<ID=ADF,Number=R,Type=Integer,Description="Read depth of each allele on the forward strand">"#,
+    );
+    header.push_record(
+        br#"##FORMAT=<ID=ADR,Number=R,Type=Integer,Description="Read depth of each allele on the reverse strand">"#,
+    );
+    if count_indel {
+        header.push_record(
+            br#"##FORMAT=<ID=INS,Number=3,Type=Integer,Description="Insertions after the site: number of reads, min and max length">"#,
+        );
+        header.push_record(
+            br#"##FORMAT=<ID=DEL,Number=3,Type=Integer,Description="Deletions after the site: number of reads, min and max length">"#,
+        );
+    }
+    for name in sample_names {
+        header.push_sample(name.as_bytes());
+    }
+    header
+}
+
+/// A VCF line of the site at 0-based `pos`. `ref_seq` is the reference from the site on, long
+/// enough to cover the deletions. Non-reference bases are the ALT alleles, and so are indels
+/// with `count_indel`, all padded to the reference allele that spans the longest deletion.
+pub fn format_record(
+    chrom: &str,
+    pos: u32,
+    ref_seq: &[u8],
+    samples: &[SiteCounts],
+    count_indel: bool,
+) -> String {
+    let ref_base = ref_seq[0].to_ascii_uppercase();
+    let mut alleles = vec![Allele::Ref];
+    for (i, b) in BASES.iter().enumerate() {
+        let allele = Allele::Base(i);
+        if *b != ref_base
+            && samples
+                .iter()
+                .any(|s| s.count(&allele, ref_base, 0) + s.count(&allele, ref_base, 1) > 0)
+        {
+            alleles.push(allele);
+        }
+    }
+    if count_indel {
+        let ins: BTreeSet<&[u8]> = samples
+            .iter()
+            .flat_map(|s| s.ins[0].iter().chain(s.ins[1]))
+            .map(|s| s.as_slice())
+            .collect();
+        alleles.extend(ins.into_iter().map(Allele::Ins));
+        let del: BTreeSet<u32> = samples
+            .iter()
+            .flat_map(|s| s.del[0].iter().chain(s.del[1]))
+            .copied()
+            .collect();
+        alleles.extend(del.into_iter().map(Allele::Del));
+    }
+
+    let max_del = alleles
+        .iter()
+        .filter_map(|a| match a {
+            Allele::Del(len) => Some(*len as usize),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+    let ref_allele = ref_seq[..ref_seq.len().min(max_del + 1)].to_ascii_uppercase();
+    let tail = &ref_allele[1..];
+    let alt = alleles[1..]
+        .iter()
+        .map(|a| {
+            let seq = match a {
+                Allele::Ref => unreachable!(),
+                Allele::Base(i) => [&[BASES[*i]], tail].concat(),
+                Allele::Ins(s) => [&[ref_base], *s, tail].concat(),
+                Allele::Del(len) => {
+                    [&[ref_base], tail.get(*len as usize..).unwrap_or(b"")].concat()
+                }
+            };
+            String::from_utf8_lossy(&seq).to_string()
+        })
+        .collect::<Vec<_>>();
+
+    let mut fields = vec![
+        chrom.to_string(),
+        (pos + 1).to_string(),
+        ".".to_string(),
+        String::from_utf8_lossy(&ref_allele).to_string(),
+        if alt.is_empty() {
+            ".".to_string()
+        } else {
+            alt.join(",")
+        },
+        ".".to_string(),
+        ".".to_string(),
+        format!("DP={}", samples.iter().map(|s| s.depth()).sum::<usize>()),
+        if count_indel {
+            "AD:DP:ADF:ADR:INS:DEL"
+        } else {
+            "AD:DP:ADF:ADR"
+        }
+        .to_string(),
+    ];
+    for s in samples {
+        let depths = |strands: &[usize]| {
+            alleles
+                .iter()
+                .map(|a| {
+                    strands
+                        .iter()
+                        .map(|&strand| s.count(a, ref_base, strand))
+                        .sum::<usize>()
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let mut field = format!(
+            "{}:{}:{}:{}",
+            depths(&[0, 1]),
+            s.depth(),
+            depths(&[0]),
+            depths(&[1])
+        );
+        if count_indel {
+            let ins = s.ins[0].iter().chain(s.ins[1]).map(|s| s.len() as u32);
+            let del = s.del[0].iter().chain(s.del[1]).copied();
+            field += &format!(":{}:{}", length_summary(ins), length_summary(del));
+        }
+        fields.push(field);
+    }
+    fields.join("\t") + "\n"
+}
+
+// reset errno, so that it tells the error of the next call only
+fn clear_errno() {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        *libc::__errno_location() = 0
+    };
+    #[cfg(target_os = "macos")]
+    unsafe {
+        *libc::__error() = 0
+    };
+}
+
+// number of reads, min and max length
+fn length_summary(lengths: impl Iterator<Item = u32> + Clone) -> String {
+    match (lengths.clone().min(), lengths.clone().max()) {
+        (Some(min), Some(max)) => format!("{},{},{}", lengths.count(), min, max),
+        _ => "0,.,.".to_string(),
+    }
+}
+
+/// Writer of VCF (plain or bgzip compressed) or BCF, to a file or stdout. Compressed files are
+/// indexed once finished, `.tbi` for VCF and `.csi` for BCF.
+pub struct Writer {
+    inner: bcf::Writer,
+    record: bcf::Record,
+    path: Option<PathBuf>,
+    format: OutputFormat,
+    line: Vec<u8>,
+}
+
+impl Writer {
+    pub fn create(
+        path: Option<&Path>,
+        format: OutputFormat,
+        header: &bcf::Header,
+        hts_threads: usize,
+    ) -> Result<Self> {
+        let bcf_format = match format {
+            OutputFormat::Bcf => bcf::Format::Bcf,
+            _ => bcf::Format::Vcf,
+        };
+        let uncompressed = format == OutputFormat::Vcf && !output::is_indexed(path, format);
+        let mut inner = match path {
+            Some(p) => bcf::Writer::from_path(p, header, uncompressed, bcf_format),
+            None => bcf::Writer::from_stdout(header, uncompressed, bcf_format),
+        }
+        .map_err(|e| Error::Write(io::Error::other(format!("Failed to create output: {}", e))))?;
+        if hts_threads > 0 {
+            inner
+                .set_threads(hts_threads)
+                .map_err(|e| Error::Write(io::Error::other(e)))?;
+        }
+        Ok(Writer {
+            record: inner.empty_record(),
+            inner,
+            path: path.map(|p| p.to_path_buf()),
+            format,
+            line: Vec::new(),
+        })
+    }
+
+    /// Write a VCF line (as from `format_record`) in the format of the writer.
+    pub fn write_line(&mut self, line: &str) -> Result<()> {
+        self.line.clear();
+        self.line.extend_from_slice(line.trim_end().as_bytes());
+        self.line.push(0);
+        let mut s = htslib::kstring_t {
+            l: (self.line.len() - 1) as _,
+            m: self.line.len() as _,
+            s: self.line.as_mut_ptr() as *mut _,
+        };
+        if unsafe { htslib::vcf_parse(&mut s, self.inner.header().inner, self.record.inner) } != 0 {
+            return Err(Error::Write(io::Error::other(format!(
+                "Invalid VCF record `{}`.",
+                line.trim_end()
+            ))));
+        }
+        clear_errno();
+        match self.inner.write(&self.record) {
+            Ok(()) => Ok(()),
+            // a closed pipe is told by errno of the failed write only
+            Err(_) if io::Error::last_os_error().raw_os_error() == Some(libc::EPIPE) => {
+                Err(Error::Write(io::ErrorKind::BrokenPipe.into()))
+            }
+            Err(e) => Err(Error::Write(io::Error::other(format!(
+                "Failed to write record `{}`: {}",
+                line.split('\t').take(2).collect::<Vec<_>>().join(":"),
+                e
+            )))),
+        }
+    }
+
+    /// Close the output and build the index of a compressed file.
+    pub fn finish(self) -> Result<()> {
+        let indexed = output::is_indexed(self.path.as_deref(), self.format);
+        drop(self.record);
+        drop(self.inner);
+        let path = match &self.path {
+            Some(path) if indexed => path,
+            _ => return Ok(()),
+        };
+        let c_path = utils::path_to_cstring(path)
+            .ok_or_else(|| Error::Index(format!("Invalid path {:?}.", path)))?;
+        let ret = match self.format {
+            OutputFormat::Bcf => unsafe { htslib::bcf_index_build(c_path.as_ptr(), 14) },
+            _ => unsafe { htslib::tbx_index_build(c_path.as_ptr(), 0, &htslib::tbx_conf_vcf) },
+        };
+        if ret != 0 {
+            return Err(Error::Index(format!(
+                "Failed to build index of {:?}.",
+                path
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snv_and_deletion_share_padded_ref() {
+        // 6 reads of A on the forward strand, one of them followed by a deletion, and 2 of G
+        let bases = [6, 0, 2, 0];
+        let anchors = [1, 0, 0, 0];
+        let counts = SiteCounts {
+            bases: [&bases, &[0; 4]],
+            ins: [&[], &[]],
+            del: [&[1], &[]],
+            anchors: [&anchors, &[0; 4]],
+        };
+        assert_eq!(
+            format_record("XII", 100, b"ATG", &[counts], true),
+            "XII\t101\t.\tAT\tGT,A\t.\t.\tDP=8\tAD:DP:ADF:ADR:INS:DEL\t5,2,1:8:5,2,1:0,0,0:0,.,.:1,1,1\n"
+        );
+    }
+
+    #[test]
+    fn insertion_on_reverse_strand() {
+        let ins = [b"AG".to_vec()];
+        let counts = SiteCounts {
+            bases: [&[0; 4], &[0, 3, 0, 0]],
+            ins: [&[], &ins],
+            del: [&[], &[]],
+            anchors: [&[0; 4], &[0, 1, 0, 0]],
+        };
+        assert_eq!(
+            format_record("XII", 0, b"cT", &[counts], true),
+            "XII\t1\t.\tC\tCAG\t.\t.\tDP=3\tAD:DP:ADF:ADR:INS:DEL\t2,1:3:0,0:2,1:1,2,2:0,.,.\n"
+        );
+    }
+
+    #[test]
+    fn deletion_anchored_on_alt_base() {
+        // 3 reads of A and 2 of G, one of G followed by a deletion: the deletion read is neither
+        // of the reference nor of the G allele
+        let bases = [3, 0, 2, 0];
+        let anchors = [0, 0, 1, 0];
+        let counts = SiteCounts {
+            bases: [&bases, &[0; 4]],
+            ins: [&[], &[]],
+            del: [&[2], &[]],
+            anchors: [&anchors, &[0; 4]],
+        };
+        assert_eq!(
+            format_record("XII", 100, b"ATTC", &[counts], true),
+            "XII\t101\t.\tATT\tGTT,A\t.\t.\tDP=5\tAD:DP:ADF:ADR:INS:DEL\t3,1,1:5:3,1,1:0,0,0:0,.,.:1,2,2\n"
+        );
+        // a deletion anchored on the only G read leaves no G allele
+        let bases = [3, 0, 1, 0];
+        let counts = SiteCounts {
+            bases: [&bases, &[0; 4]],
+            ins: [&[], &[]],
+            del: [&[2], &[]],
+            anchors: [&anchors, &[0; 4]],
+        };
+        assert_eq!(
+            format_record("XII", 100, b"ATTC", &[counts], true),
+            "XII\t101\t.\tATT\tA\t.\t.\tDP=4\tAD:DP:ADF:ADR:INS:DEL\t3,1:4:3,1:0,0:0,.,.:1,2,2\n"
+        );
+    }
+
+    #[test]
+    fn reference_only_sites_of_samples() {
+        let bases = [0, 0, 0, 4];
+        let samples = [
+            SiteCounts {
+                bases: [&bases, &[0; 4]],
+                ins: [&[], &[]],
+                del: [&[], &[]],
+                anchors: [&[0; 4], &[0; 4]],
+            },
+            SiteCounts {
+                bases: [&[0; 4], &[0; 4]],
+                ins: [&[], &[]],
+                del: [&[], &[]],
+                anchors: [&[0; 4], &[0; 4]],
+            },
+        ];
+        assert_eq!(
+            format_record("XII", 9, b"T", &samples, false),
+            "XII\t10\t.\tT\t.\t.\t.\tDP=4\tAD:DP:ADF:ADR\t4:4:4:0\t0:0:0:0\n"
+        );
+    }
+}